@@ -9,15 +9,18 @@ fn main() {
 #[cfg(feature = "binary")]
 mod binary {
     use quote::quote;
-    use std::{convert::TryInto, path::PathBuf};
+    use std::{
+        convert::TryInto,
+        fs,
+        path::{Path, PathBuf},
+    };
 
     pub fn main() {
         use llvm_tools_build as llvm_tools;
         use std::{
             env,
-            fs::{self, File},
+            fs::File,
             io::Write,
-            path::Path,
             process::{self, Command},
         };
         use toml::Value;
@@ -83,57 +86,212 @@ mod binary {
             }
         };
 
-        // check that kernel executable has code in it
-        let llvm_size = llvm_tools
-            .tool(&llvm_tools::exe("llvm-size"))
-            .expect("llvm-size not found in llvm-tools");
-        let mut cmd = Command::new(llvm_size);
-        cmd.arg(&kernel);
-        let output = cmd.output().expect("failed to run llvm-size");
-        let output_str = String::from_utf8_lossy(&output.stdout);
-        let second_line_opt = output_str.lines().skip(1).next();
-        let second_line = second_line_opt.expect(&format!(
-            "unexpected llvm-size line output:\n{}",
-            output_str
-        ));
-        let text_size_opt = second_line.split_ascii_whitespace().next();
-        let text_size =
-            text_size_opt.expect(&format!("unexpected llvm-size output:\n{}", output_str));
-        if text_size == "0" {
-            panic!("Kernel executable has an empty text section. Perhaps the entry point was set incorrectly?\n\n\
-            Kernel executable at `{}`\n", kernel.display());
-        }
-
-        // strip debug symbols from kernel for faster loading
         let stripped_kernel_file_name = format!("kernel_stripped-{}", kernel_file_name);
         let stripped_kernel = out_dir.join(&stripped_kernel_file_name);
         let objcopy = llvm_tools
             .tool(&llvm_tools::exe("llvm-objcopy"))
             .expect("llvm-objcopy not found in llvm-tools");
-        let mut cmd = Command::new(&objcopy);
-        cmd.arg("--strip-debug");
-        cmd.arg(&kernel);
-        cmd.arg(&stripped_kernel);
-        let exit_status = cmd
-            .status()
-            .expect("failed to run objcopy to strip debug symbols");
-        if !exit_status.success() {
-            eprintln!("Error: Stripping debug symbols failed");
-            process::exit(1);
+        // Linux kernel images are no ELF files, so they are embedded unmodified
+        if is_bzimage(&kernel) {
+            fs::copy(&kernel, &stripped_kernel).expect("failed to copy Linux kernel image");
+        } else {
+            // check that kernel executable has code in it
+            let llvm_size = llvm_tools
+                .tool(&llvm_tools::exe("llvm-size"))
+                .expect("llvm-size not found in llvm-tools");
+            let mut cmd = Command::new(llvm_size);
+            cmd.arg(&kernel);
+            let output = cmd.output().expect("failed to run llvm-size");
+            let output_str = String::from_utf8_lossy(&output.stdout);
+            let second_line_opt = output_str.lines().skip(1).next();
+            let second_line = second_line_opt.expect(&format!(
+                "unexpected llvm-size line output:\n{}",
+                output_str
+            ));
+            let text_size_opt = second_line.split_ascii_whitespace().next();
+            let text_size =
+                text_size_opt.expect(&format!("unexpected llvm-size output:\n{}", output_str));
+            if text_size == "0" {
+                panic!("Kernel executable has an empty text section. Perhaps the entry point was set incorrectly?\n\n\
+                Kernel executable at `{}`\n", kernel.display());
+            }
+
+            // strip debug symbols from kernel for faster loading
+            let mut cmd = Command::new(&objcopy);
+            cmd.arg("--strip-debug");
+            cmd.arg(&kernel);
+            cmd.arg(&stripped_kernel);
+            let exit_status = cmd
+                .status()
+                .expect("failed to run objcopy to strip debug symbols");
+            if !exit_status.success() {
+                eprintln!("Error: Stripping debug symbols failed");
+                process::exit(1);
+            }
         }
 
+        // Parse configuration from the config section of the kernel executable, which is created
+        // by the `bootloader::config!` macro, or from the kernel's Cargo.toml
+        let embedded_config = if is_bzimage(&kernel) {
+            None
+        } else {
+            read_embedded_config(&kernel)
+        };
+        // relative module paths are resolved against the directory of the kernel's Cargo.toml, or
+        // against the directory of the kernel executable if no Cargo.toml is given
+        let config_dir = match env::var_os("KERNEL_MANIFEST") {
+            Some(path) => PathBuf::from(path).parent().unwrap().to_owned(),
+            None => kernel.parent().unwrap().to_owned(),
+        };
+        let mut config = None;
+        // reported by the builder, so that its hints point to the right place
+        let config_source = match embedded_config {
+            Some(_) => "embedded",
+            None => "manifest",
+        };
+        let config_stream = match embedded_config {
+            Some(contents) => {
+                let result = contents
+                    .parse::<Value>()
+                    .map_err(|err| err.to_string())
+                    .and_then(|config_table| parse_config(config_table, &config_dir));
+                match result {
+                    Ok(p_config) => {
+                        let stream = quote! { #p_config };
+                        config = Some(p_config);
+                        stream
+                    }
+                    Err(err) => {
+                        let err = format!(
+                            "failed to parse bootloader config embedded in {}:\n\n{}",
+                            kernel.display(),
+                            err
+                        );
+                        quote! {
+                            compile_error!(#err)
+                        }
+                    }
+                }
+            }
+            None => match env::var("KERNEL_MANIFEST") {
+                Err(env::VarError::NotPresent) => {
+                    panic!("The KERNEL_MANIFEST environment variable must be set for building the bootloader, \
+                     unless the kernel embeds its config through the `bootloader::config!` macro.\n\n\
+                     Please use `cargo builder` for building.");
+                }
+                Err(env::VarError::NotUnicode(_)) => {
+                    panic!("The KERNEL_MANIFEST environment variable contains invalid unicode")
+                }
+                Ok(path)
+                    if Path::new(&path).file_name().and_then(|s| s.to_str())
+                        != Some("Cargo.toml") =>
+                {
+                    let err = format!(
+                        "The given `--kernel-manifest` path `{}` does not \
+                        point to a `Cargo.toml`",
+                        path,
+                    );
+                    quote! { compile_error!(#err) }
+                }
+                Ok(path) if !Path::new(&path).exists() => {
+                    let err = format!(
+                        "The given `--kernel-manifest` path `{}` does not exist.",
+                        path
+                    );
+                    quote! {
+                        compile_error!(#err)
+                    }
+                }
+                Ok(path) => {
+                    println!("cargo:rerun-if-changed={}", path);
+
+                    let contents = fs::read_to_string(&path).expect(&format!(
+                        "failed to read kernel manifest file (path: {})",
+                        path
+                    ));
+
+                    let manifest = contents
+                        .parse::<Value>()
+                        .expect("failed to parse kernel's Cargo.toml");
+
+                    if manifest
+                        .get("dependencies")
+                        .and_then(|d| d.get("bootloader"))
+                        .or_else(|| {
+                            manifest
+                                .get("target")
+                                .and_then(|table| table.get(r#"cfg(target_arch = "x86_64")"#))
+                                .and_then(|table| table.get("dependencies"))
+                                .and_then(|table| table.get("bootloader"))
+                        })
+                        .is_some()
+                    {
+                        // it seems to be the correct Cargo.toml
+                        let config_table = manifest
+                            .get("package")
+                            .and_then(|table| table.get("metadata"))
+                            .and_then(|table| table.get("bootloader"))
+                            .cloned()
+                            .unwrap_or_else(|| toml::Value::Table(toml::map::Map::new()));
+
+                        let manifest_dir = Path::new(&path).parent().unwrap();
+                        let result = parse_config(config_table, manifest_dir);
+                        match result {
+                            Ok(p_config) => {
+                                let stream = quote! { #p_config };
+                                config = Some(p_config);
+                                stream
+                            }
+                            Err(err) => {
+                                let err = format!(
+                                    "failed to parse bootloader config in {}:\n\n{}",
+                                    path, err
+                                );
+                                quote! {
+                                    compile_error!(#err)
+                                }
+                            }
+                        }
+                    } else {
+                        let err = format!(
+                            "no bootloader dependency in {}\n\n  The \
+                        `--kernel-manifest` path should point to the `Cargo.toml` \
+                        of the kernel.",
+                            path
+                        );
+                        quote! {
+                            compile_error!(#err)
+                        }
+                    }
+                }
+            },
+        };
+        let config = config;
+
+        // compress the kernel if requested
+        let compression = config.as_ref().and_then(|c| c.compression);
+        let (embedded_kernel_file_name, embedded_kernel) = match compression {
+            None => (stripped_kernel_file_name, stripped_kernel),
+            Some(compression) => {
+                let file_name = format!("kernel_compressed-{}", kernel_file_name);
+                let path = out_dir.join(&file_name);
+                compress_file(compression, &stripped_kernel, &path);
+                (file_name, path)
+            }
+        };
+
         if cfg!(feature = "uefi_bin") {
             // write file for including kernel in binary
             let file_path = out_dir.join("kernel_info.rs");
             let mut file = File::create(file_path).expect("failed to create kernel_info.rs");
-            let kernel_size = fs::metadata(&stripped_kernel)
-                .expect("Failed to read file metadata of stripped kernel")
+            let kernel_size = fs::metadata(&embedded_kernel)
+                .expect("Failed to read file metadata of embedded kernel")
                 .len();
             file.write_all(
                 format!(
                     "const KERNEL_SIZE: usize = {}; const KERNEL_BYTES: [u8; KERNEL_SIZE] = *include_bytes!(r\"{}\");",
                     kernel_size,
-                    stripped_kernel.display(),
+                    embedded_kernel.display(),
                 )
                 .as_bytes(),
             )
@@ -142,7 +300,7 @@ mod binary {
 
         if cfg!(feature = "bios_bin") {
             // wrap the kernel executable as binary in a new ELF file
-            let stripped_kernel_file_name_replaced = stripped_kernel_file_name
+            let embedded_kernel_file_name_replaced = embedded_kernel_file_name
                 .replace('-', "_")
                 .replace('.', "_");
             let kernel_bin = out_dir.join(format!("kernel_bin-{}.o", kernel_file_name));
@@ -154,18 +312,18 @@ mod binary {
             cmd.arg("--rename-section").arg(".data=.kernel");
             cmd.arg("--redefine-sym").arg(format!(
                 "_binary_{}_start=_kernel_start_addr",
-                stripped_kernel_file_name_replaced
+                embedded_kernel_file_name_replaced
             ));
             cmd.arg("--redefine-sym").arg(format!(
                 "_binary_{}_end=_kernel_end_addr",
-                stripped_kernel_file_name_replaced
+                embedded_kernel_file_name_replaced
             ));
             cmd.arg("--redefine-sym").arg(format!(
                 "_binary_{}_size=_kernel_size",
-                stripped_kernel_file_name_replaced
+                embedded_kernel_file_name_replaced
             ));
             cmd.current_dir(&out_dir);
-            cmd.arg(&stripped_kernel_file_name);
+            cmd.arg(&embedded_kernel_file_name);
             cmd.arg(&kernel_bin);
             let exit_status = cmd.status().expect("failed to run objcopy");
             if !exit_status.success() {
@@ -200,100 +358,6 @@ mod binary {
             );
         }
 
-        // Parse configuration from the kernel's Cargo.toml
-        let mut config = None;
-        let config_stream = match env::var("KERNEL_MANIFEST") {
-            Err(env::VarError::NotPresent) => {
-                panic!("The KERNEL_MANIFEST environment variable must be set for building the bootloader.\n\n\
-                 Please use `cargo builder` for building.");
-            }
-            Err(env::VarError::NotUnicode(_)) => {
-                panic!("The KERNEL_MANIFEST environment variable contains invalid unicode")
-            }
-            Ok(path)
-                if Path::new(&path).file_name().and_then(|s| s.to_str()) != Some("Cargo.toml") =>
-            {
-                let err = format!(
-                    "The given `--kernel-manifest` path `{}` does not \
-                    point to a `Cargo.toml`",
-                    path,
-                );
-                quote! { compile_error!(#err) }
-            }
-            Ok(path) if !Path::new(&path).exists() => {
-                let err = format!(
-                    "The given `--kernel-manifest` path `{}` does not exist.",
-                    path
-                );
-                quote! {
-                    compile_error!(#err)
-                }
-            }
-            Ok(path) => {
-                println!("cargo:rerun-if-changed={}", path);
-
-                let contents = fs::read_to_string(&path).expect(&format!(
-                    "failed to read kernel manifest file (path: {})",
-                    path
-                ));
-
-                let manifest = contents
-                    .parse::<Value>()
-                    .expect("failed to parse kernel's Cargo.toml");
-
-                if manifest
-                    .get("dependencies")
-                    .and_then(|d| d.get("bootloader"))
-                    .or_else(|| {
-                        manifest
-                            .get("target")
-                            .and_then(|table| table.get(r#"cfg(target_arch = "x86_64")"#))
-                            .and_then(|table| table.get("dependencies"))
-                            .and_then(|table| table.get("bootloader"))
-                    })
-                    .is_some()
-                {
-                    // it seems to be the correct Cargo.toml
-                    let config_table = manifest
-                        .get("package")
-                        .and_then(|table| table.get("metadata"))
-                        .and_then(|table| table.get("bootloader"))
-                        .cloned()
-                        .unwrap_or_else(|| toml::Value::Table(toml::map::Map::new()));
-
-                    let result = config_table.try_into::<ParsedConfig>();
-                    match result {
-                        Ok(p_config) => {
-                            let stream = quote! { #p_config };
-                            config = Some(p_config);
-                            stream
-                        }
-                        Err(err) => {
-                            let err = format!(
-                                "failed to parse bootloader config in {}:\n\n{}",
-                                path,
-                                err.to_string()
-                            );
-                            quote! {
-                                compile_error!(#err)
-                            }
-                        }
-                    }
-                } else {
-                    let err = format!(
-                        "no bootloader dependency in {}\n\n  The \
-                    `--kernel-manifest` path should point to the `Cargo.toml` \
-                    of the kernel.",
-                        path
-                    );
-                    quote! {
-                        compile_error!(#err)
-                    }
-                }
-            }
-        };
-        let config = config;
-
         // Write config to file
         let file_path = out_dir.join("bootloader_config.rs");
         let mut file = File::create(file_path).expect("failed to create config file");
@@ -303,7 +367,7 @@ mod binary {
                 /// Public so that `bin/uefi.rs` can read framebuffer configuration.
                 #[allow(unused)]
                 pub mod parsed_config {
-                    use crate::config::{Config, ModuleEntry};
+                    use crate::config::{Compression, Config, ModuleEntry};
                     /// The parsed configuration given by the user.
                     pub const CONFIG: Config = #config_stream;
                 }
@@ -332,17 +396,32 @@ mod binary {
         ))
         .expect("writing config failed");
 
-        let kernel_manifest = PathBuf::from(env::var("KERNEL_MANIFEST").unwrap());
-        let kernel_manifest_dir = kernel_manifest.parent().unwrap();
-
         // Write module information
-        let module_config = if let Some(modules) = config.map(|c| c.modules) {
+        let module_config = if let Some(modules) = config.as_ref().map(|c| &c.modules) {
             let modules_json = modules
                 .iter()
                 .map(|module| {
+                    let source = config_dir.join(&module.path);
+                    let path = match compression {
+                        None => source,
+                        Some(compression) => {
+                            let path = out_dir.join("compressed_modules").join(&module.name);
+                            fs::create_dir_all(path.parent().unwrap())
+                                .expect("failed to create compressed module dir");
+                            compress_file(compression, &source, &path);
+                            path
+                        }
+                    };
+                    let sha256 = module
+                        .sha256
+                        .iter()
+                        .map(|b| format!("{:02x}", b))
+                        .collect::<String>();
                     json::object! {
-                        path: kernel_manifest_dir.join(&module.path).display().to_string(),
+                        path: path.display().to_string(),
                         name: module.name.clone(),
+                        args: module.args.clone(),
+                        sha256: sha256,
                     }
                 })
                 .collect::<json::Array>();
@@ -350,11 +429,52 @@ mod binary {
         } else {
             "[]".into()
         };
+        if let Some(config) = &config {
+            for spec in &config.module_specs {
+                // also catches files that are added to the directory
+                let dir = match spec {
+                    ModuleSpec::File(_) => continue,
+                    ModuleSpec::Dir(dir) => config_dir.join(&dir.dir),
+                    ModuleSpec::Glob(glob) => config_dir.join(glob_base_dir(&glob.glob)),
+                };
+                println!("cargo:rerun-if-changed={}", dir.display());
+            }
+            for module in &config.modules {
+                let path = config_dir.join(&module.path);
+                println!("cargo:rerun-if-changed={}", path.display());
+            }
+        }
         let module_json_path = out_dir.join("module_config.json");
         let mut file = File::create(module_json_path).expect("failed to create module config file");
         file.write_all(module_config.as_bytes())
             .expect("writing module_config.json failed");
 
+        // Write the config options that the builder checks against the kernel
+        let address = |address: Option<AlignedAddress>| address.map(|a| a.0);
+        let preflight_config = match &config {
+            Some(config) => json::object! {
+                "config-source": config_source,
+                "strict-wx": config.strict_wx,
+                "map-physical-memory": config.map_physical_memory,
+                "physical-memory-offset": address(config.physical_memory_offset),
+                "map-page-table-recursively": config.map_page_table_recursively,
+                "recursive-index": config.recursive_index,
+                "kernel-stack-address": address(config.kernel_stack_address),
+                "kernel-stack-size": address(config.kernel_stack_size),
+                "kernel-stack-guard-size": address(config.kernel_stack_guard_size),
+                "boot-info-address": address(config.boot_info_address),
+                "framebuffer-address": address(config.framebuffer_address),
+                "modules-address": address(config.modules_address),
+                "kernel-cmdline": config.kernel_cmdline.as_str(),
+            },
+            None => json::object! {},
+        };
+        let preflight_json_path = out_dir.join("preflight_config.json");
+        let mut file =
+            File::create(preflight_json_path).expect("failed to create preflight config file");
+        file.write_all(json::stringify(preflight_config).as_bytes())
+            .expect("writing preflight_config.json failed");
+
         println!("cargo:rerun-if-env-changed=KERNEL");
         println!("cargo:rerun-if-env-changed=KERNEL_MANIFEST");
         println!("cargo:rerun-if-changed={}", kernel.display());
@@ -388,8 +508,49 @@ mod binary {
         pub framebuffer_address: Option<AlignedAddress>,
         pub minimum_framebuffer_height: Option<usize>,
         pub minimum_framebuffer_width: Option<usize>,
-        #[serde(default)]
+        #[serde(default, rename = "modules")]
+        pub module_specs: Vec<ModuleSpec>,
+        /// The module entries after expanding all `dir` and `glob` specs.
+        #[serde(skip)]
         pub modules: Vec<ModuleEntry>,
+        pub modules_address: Option<AlignedAddress>,
+        pub compression: Option<Compression>,
+        #[serde(default)]
+        pub aslr: bool,
+        #[serde(default)]
+        pub kernel_huge_pages: bool,
+        #[serde(default)]
+        pub copy_kernel_segments: bool,
+        #[serde(default)]
+        pub keep_symbols: bool,
+        #[serde(default)]
+        pub strict_wx: bool,
+        #[serde(default)]
+        pub map_tls_block: bool,
+        pub kernel_stack_guard_size: Option<AlignedAddress>,
+        #[serde(default)]
+        pub kernel_stack_canary: bool,
+        #[serde(default)]
+        pub kernel_cmdline: String,
+    }
+
+    /// Must be always identical with the enum in `src/config.rs`
+    #[derive(Debug, Clone, Copy, serde::Deserialize)]
+    #[serde(rename_all = "kebab-case")]
+    enum Compression {
+        Lz4,
+    }
+
+    /// An entry of the `modules` array in the config.
+    #[derive(Debug, Clone, serde::Deserialize)]
+    #[serde(untagged)]
+    enum ModuleSpec {
+        /// A single module file.
+        File(ModuleEntry),
+        /// All files in a directory (recursively).
+        Dir(ModuleDir),
+        /// All files matching a glob pattern.
+        Glob(ModuleGlob),
     }
 
     #[derive(Debug, Clone, serde::Deserialize)]
@@ -397,15 +558,262 @@ mod binary {
     struct ModuleEntry {
         pub name: String,
         pub path: PathBuf,
+        #[serde(default)]
+        pub args: String,
+        /// SHA-256 digest of the module file, computed after parsing.
+        #[serde(skip)]
+        pub sha256: [u8; 32],
+        pub align: Option<u64>,
+        pub max_phys_addr: Option<AlignedAddress>,
+    }
+
+    /// Expands into one module per file in `dir`, named `prefix` + the path relative to `dir`.
+    #[derive(Debug, Clone, serde::Deserialize)]
+    #[serde(rename_all = "kebab-case", deny_unknown_fields)]
+    struct ModuleDir {
+        pub dir: PathBuf,
+        #[serde(default)]
+        pub prefix: String,
+        #[serde(default)]
+        pub args: String,
+        pub align: Option<u64>,
+        pub max_phys_addr: Option<AlignedAddress>,
+    }
+
+    /// Expands into one module per matched file, named `prefix` + the path relative to the
+    /// kernel manifest directory.
+    #[derive(Debug, Clone, serde::Deserialize)]
+    #[serde(rename_all = "kebab-case", deny_unknown_fields)]
+    struct ModuleGlob {
+        pub glob: String,
+        #[serde(default)]
+        pub prefix: String,
+        #[serde(default)]
+        pub args: String,
+        pub align: Option<u64>,
+        pub max_phys_addr: Option<AlignedAddress>,
+    }
+
+    /// Expands all `dir` and `glob` module specs into single module entries.
+    ///
+    /// The resulting order is deterministic: specs are expanded in the given order and the
+    /// files of each spec are sorted by path.
+    fn expand_modules(
+        specs: &[ModuleSpec],
+        manifest_dir: &Path,
+    ) -> Result<Vec<ModuleEntry>, String> {
+        fn relative_name(prefix: &str, path: &Path) -> Result<String, String> {
+            let components = path
+                .components()
+                .map(|c| c.as_os_str().to_str())
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| format!("module path `{}` is not valid UTF-8", path.display()))?;
+            Ok(format!("{}{}", prefix, components.join("/")))
+        }
+
+        fn walk_dir(dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), String> {
+            let entries = fs::read_dir(dir)
+                .map_err(|err| format!("failed to read module dir `{}`: {}", dir.display(), err))?;
+            let mut paths = entries
+                .map(|entry| entry.map(|e| e.path()))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|err| format!("failed to read module dir `{}`: {}", dir.display(), err))?;
+            paths.sort();
+            for path in paths {
+                if path.is_dir() {
+                    walk_dir(&path, files)?;
+                } else {
+                    files.push(path);
+                }
+            }
+            Ok(())
+        }
+
+        let mut modules = Vec::new();
+        for spec in specs {
+            match spec {
+                ModuleSpec::File(entry) => modules.push(entry.clone()),
+                ModuleSpec::Dir(spec) => {
+                    let dir = manifest_dir.join(&spec.dir);
+                    let mut files = Vec::new();
+                    walk_dir(&dir, &mut files)?;
+                    for file in files {
+                        let relative = file.strip_prefix(&dir).unwrap();
+                        modules.push(ModuleEntry {
+                            name: relative_name(&spec.prefix, relative)?,
+                            path: spec.dir.join(relative),
+                            args: spec.args.clone(),
+                            sha256: [0; 32],
+                            align: spec.align,
+                            max_phys_addr: spec.max_phys_addr,
+                        });
+                    }
+                }
+                ModuleSpec::Glob(spec) => {
+                    let pattern = manifest_dir.join(&spec.glob);
+                    let pattern = pattern
+                        .to_str()
+                        .ok_or_else(|| format!("module glob `{}` is not valid UTF-8", spec.glob))?;
+                    let paths = glob::glob(pattern)
+                        .map_err(|err| format!("invalid module glob `{}`: {}", spec.glob, err))?;
+                    let mut files = paths.collect::<Result<Vec<_>, _>>().map_err(|err| {
+                        format!("failed to expand module glob `{}`: {}", spec.glob, err)
+                    })?;
+                    files.retain(|path| !path.is_dir());
+                    files.sort();
+                    for file in files {
+                        let relative = file.strip_prefix(manifest_dir).map_err(|_| {
+                            format!(
+                                "module glob `{}` matched `{}` outside of the kernel directory",
+                                spec.glob,
+                                file.display()
+                            )
+                        })?;
+                        modules.push(ModuleEntry {
+                            name: relative_name(&spec.prefix, relative)?,
+                            path: relative.to_owned(),
+                            args: spec.args.clone(),
+                            sha256: [0; 32],
+                            align: spec.align,
+                            max_phys_addr: spec.max_phys_addr,
+                        });
+                    }
+                }
+            }
+        }
+
+        let mut names = std::collections::BTreeSet::new();
+        for module in &modules {
+            if !names.insert(&module.name) {
+                return Err(format!("duplicate module name `{}`", module.name));
+            }
+        }
+
+        Ok(modules)
+    }
+
+    /// Parses the given `package.metadata.bootloader` table and expands its module entries.
+    ///
+    /// Relative module paths are resolved against `config_dir`.
+    fn parse_config(config_table: toml::Value, config_dir: &Path) -> Result<ParsedConfig, String> {
+        let mut p_config = config_table
+            .try_into::<ParsedConfig>()
+            .map_err(|err| err.to_string())?;
+        p_config.modules = expand_modules(&p_config.module_specs, config_dir)?;
+        for module in &mut p_config.modules {
+            check_module(module)?;
+            module.sha256 = hash_module(&config_dir.join(&module.path))?;
+        }
+        Ok(p_config)
+    }
+
+    /// Returns the leading components of the given glob pattern that contain no wildcards.
+    ///
+    /// All files that the pattern matches are in this directory (or its subdirectories).
+    fn glob_base_dir(pattern: &str) -> PathBuf {
+        Path::new(pattern)
+            .components()
+            .take_while(|c| {
+                !c.as_os_str()
+                    .to_string_lossy()
+                    .contains(&['*', '?', '['][..])
+            })
+            .collect()
+    }
+
+    /// Checks the keys of the given module entry that can't be checked through their type.
+    fn check_module(module: &ModuleEntry) -> Result<(), String> {
+        // the name is used as path on the UEFI boot partition
+        let valid_component = |c: &str| !c.is_empty() && c != "." && c != ".." && !c.contains('\\');
+        if !module.name.split('/').all(valid_component) {
+            return Err(format!(
+                "Module name must be a relative `/`-separated path without empty, `.`, or `..` \
+                components: {:?}",
+                module.name
+            ));
+        }
+        if let Some(align) = module.align.filter(|align| !align.is_power_of_two()) {
+            return Err(format!(
+                "Module alignment must be a power of two: {:#x} (module {:?})",
+                align, module.name
+            ));
+        }
+        if let Some(AlignedAddress(0)) = module.max_phys_addr {
+            return Err(format!(
+                "Module `max-phys-addr` must not be zero (module {:?})",
+                module.name
+            ));
+        }
+        Ok(())
+    }
+
+    /// Reads the config that is embedded in the given kernel executable by the
+    /// `bootloader::config!` macro.
+    ///
+    /// Returns `None` if the kernel has no config section. Invalid kernel executables are
+    /// treated like kernels without config section, since they are reported by the `builder`.
+    fn read_embedded_config(kernel: &Path) -> Option<String> {
+        use xmas_elf_build::ElfFile;
+
+        let bytes = fs::read(kernel).ok()?;
+        let elf_file = ElfFile::new(&bytes).ok()?;
+        let section = elf_file.find_section_by_name(".bootloader-config")?;
+        let data = section.raw_data(&elf_file);
+        Some(String::from_utf8_lossy(data).into_owned())
+    }
+
+    /// Computes the SHA-256 digest of the given module file.
+    ///
+    /// The digest is embedded in the config so that the bootloader can detect corrupted modules.
+    fn hash_module(path: &Path) -> Result<[u8; 32], String> {
+        use sha2::{Digest, Sha256};
+
+        let contents = fs::read(path)
+            .map_err(|err| format!("failed to read module `{}`: {}", path.display(), err))?;
+        Ok(Sha256::digest(&contents).into())
+    }
+
+    /// Checks whether the given kernel file is a Linux kernel image in the `bzImage` format.
+    ///
+    /// The check only looks for the magic values of the setup header. The header is validated
+    /// by the `builder` binary and by the bootloader.
+    fn is_bzimage(path: &Path) -> bool {
+        use std::{fs::File, io::Read};
+
+        let mut start = Vec::new();
+        let read = File::open(path).and_then(|file| file.take(0x206).read_to_end(&mut start));
+        read.is_ok()
+            && start.len() == 0x206
+            && start[0x1fe..0x200] == [0x55, 0xaa]
+            && &start[0x202..0x206] == b"HdrS"
+    }
+
+    /// Compresses the file at `source` with the given algorithm and writes the result to `target`.
+    fn compress_file(compression: Compression, source: &Path, target: &Path) {
+        let data = fs::read(source)
+            .unwrap_or_else(|err| panic!("failed to read `{}`: {}", source.display(), err));
+        let compressed = match compression {
+            Compression::Lz4 => {
+                // the uncompressed size is prepended as `u32`
+                assert!(
+                    data.len() <= u32::MAX as usize,
+                    "`{}` is too large for lz4 compression",
+                    source.display()
+                );
+                lz4_flex::compress_prepend_size(&data)
+            }
+        };
+        fs::write(target, compressed)
+            .unwrap_or_else(|err| panic!("failed to write `{}`: {}", target.display(), err));
     }
 
     /// Convert to tokens suitable for initializing the `Config` struct.
+    fn optional(value: Option<impl quote::ToTokens>) -> proc_macro2::TokenStream {
+        value.map(|v| quote!(Some(#v))).unwrap_or(quote!(None))
+    }
+
     impl quote::ToTokens for ParsedConfig {
         fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
-            fn optional(value: Option<impl quote::ToTokens>) -> proc_macro2::TokenStream {
-                value.map(|v| quote!(Some(#v))).unwrap_or(quote!(None))
-            }
-
             let map_physical_memory = self.map_physical_memory;
             let map_page_table_recursively = self.map_page_table_recursively;
             let map_framebuffer = self.map_framebuffer;
@@ -418,6 +826,17 @@ mod binary {
             let minimum_framebuffer_height = optional(self.minimum_framebuffer_height);
             let minimum_framebuffer_width = optional(self.minimum_framebuffer_width);
             let modules = &self.modules[..];
+            let modules_address = optional(self.modules_address);
+            let compression = optional(self.compression);
+            let aslr = self.aslr;
+            let kernel_huge_pages = self.kernel_huge_pages;
+            let copy_kernel_segments = self.copy_kernel_segments;
+            let keep_symbols = self.keep_symbols;
+            let strict_wx = self.strict_wx;
+            let map_tls_block = self.map_tls_block;
+            let kernel_stack_guard_size = optional(self.kernel_stack_guard_size);
+            let kernel_stack_canary = self.kernel_stack_canary;
+            let kernel_cmdline = self.kernel_cmdline.as_str();
 
             tokens.extend(quote! { Config {
                 map_physical_memory: #map_physical_memory,
@@ -432,33 +851,49 @@ mod binary {
                 minimum_framebuffer_height: #minimum_framebuffer_height,
                 minimum_framebuffer_width: #minimum_framebuffer_width,
                 modules: &[#(#modules),*],
+                modules_address: #modules_address,
+                compression: #compression,
+                aslr: #aslr,
+                kernel_huge_pages: #kernel_huge_pages,
+                copy_kernel_segments: #copy_kernel_segments,
+                keep_symbols: #keep_symbols,
+                strict_wx: #strict_wx,
+                map_tls_block: #map_tls_block,
+                kernel_stack_guard_size: #kernel_stack_guard_size,
+                kernel_stack_canary: #kernel_stack_canary,
+                kernel_cmdline: #kernel_cmdline,
             }});
         }
     }
 
     impl quote::ToTokens for ModuleEntry {
         fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
-            let name = {
-                let name = self.name.as_str();
-                assert!(name.is_ascii(), "Module name is not ASCII: {}", name);
-                assert!(
-                    name.as_bytes().len() <= 32,
-                    "Module name is more than 32 bytes long: {}",
-                    name
-                );
-                let mut buf = [0u8; 32];
-                buf[..name.len()].copy_from_slice(name.as_bytes());
-                buf
-            };
+            let name = self.name.as_str();
             let path = self.path.to_str().unwrap();
+            let args = &self.args;
+            let sha256 = &self.sha256;
+            let align = optional(self.align);
+            let max_phys_addr = optional(self.max_phys_addr);
 
             tokens.extend(quote! { ModuleEntry {
-                name: [#(#name),*],
+                name: #name,
                 path: #path,
+                args: #args,
+                sha256: [#(#sha256),*],
+                align: #align,
+                max_phys_addr: #max_phys_addr,
             }})
         }
     }
 
+    impl quote::ToTokens for Compression {
+        fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
+            tokens.extend(match self {
+                Compression::Lz4 => quote!(Compression::Lz4),
+            });
+        }
+    }
+
     #[derive(Debug, Clone, Copy)]
     struct AlignedAddress(u64);
 
@@ -2,11 +2,12 @@
 .code16
 
 # This stage sets the target operating mode, loads the kernel from disk,
-# creates an e820 memory map, enters protected mode, and jumps to the
-# third stage.
+# creates an e820 memory map, loads the modules from disk, enters protected
+# mode, and jumps to the third stage.
 
 second_stage_start_str: .asciz "Booting (second stage)..."
 kernel_load_failed_str: .asciz "Failed to load kernel from disk"
+modules_load_failed_str: .asciz "Failed to load modules from disk"
 
 kernel_load_failed:
     mov si, offset kernel_load_failed_str
@@ -14,6 +15,12 @@ kernel_load_failed:
 kernel_load_failed_spin:
     jmp kernel_load_failed_spin
 
+modules_load_failed:
+    mov si, offset modules_load_failed_str
+    call real_mode_println
+modules_load_failed_spin:
+    jmp modules_load_failed_spin
+
 stage_2:
     mov si, offset second_stage_start_str
     call real_mode_println
@@ -82,8 +89,94 @@ load_next_kernel_block_from_disk:
     jnz load_next_kernel_block_from_disk
 
 create_memory_map:
+    # the memory map is needed to check where the modules can be placed
+    push edi
     lea di, es:[_memory_map]
     call do_e820
+    pop edi
+
+load_modules_from_disk:
+    # The builder appends a module table directly after the kernel. Load its
+    # first block and check the magic number to find out whether it exists.
+    mov si, offset dap
+    mov ah, 0x42
+    int 0x13
+    jc video_mode_config # reading behind the end of the disk -> no modules
+
+    cmp dword ptr [_kernel_buffer], 0x4f4d4c42 # "BLMO"
+    jne video_mode_config
+    cmp dword ptr [_kernel_buffer + 4], 0x42415444 # "DTAB"
+    jne video_mode_config
+
+    # place the module table at the next page boundary after the kernel
+    add edi, 0xfff
+    and edi, 0xfffff000
+    mov [module_table_addr], edi
+
+    # block count of the module table and all module contents
+    mov ecx, [_kernel_buffer + 8]
+
+check_module_memory:
+    # The modules are copied through edi, so they must end below 4GiB. They
+    # must also lie within a single usable region of the memory map, so that
+    # we don't overwrite reserved memory or memory-mapped devices.
+    test ecx, 0xff800000 # more than 4GiB?
+    jnz modules_load_failed
+    mov ebx, ecx
+    shl ebx, 9 # multiply by 512 (block size)
+    add ebx, edi # end address of the modules
+    jc modules_load_failed
+
+    movzx edx, word ptr [mmap_ent]
+    mov esi, offset _memory_map
+check_next_memory_region:
+    test edx, edx
+    jz modules_load_failed # no suitable region found
+
+    cmp dword ptr [esi + 16], 1 # usable memory?
+    jne skip_memory_region
+    cmp dword ptr [esi + 4], 0 # region starts above 4GiB?
+    jne skip_memory_region
+    cmp [esi], edi # region starts behind the module table?
+    ja skip_memory_region
+
+    # compare the 64-bit end address of the region with the module end
+    mov eax, [esi]
+    add eax, [esi + 8]
+    jc load_next_module_block_from_disk # region ends above 4GiB
+    cmp dword ptr [esi + 12], 0
+    jne load_next_module_block_from_disk # region ends above 4GiB
+    cmp eax, ebx
+    jae load_next_module_block_from_disk
+
+skip_memory_region:
+    add esi, 24 # size of an e820 entry
+    sub edx, 1
+    jmp check_next_memory_region
+
+load_next_module_block_from_disk:
+    # load block from disk
+    mov si, offset dap
+    mov ah, 0x42
+    int 0x13
+    jc modules_load_failed
+
+    # copy block behind the kernel
+    push ecx
+    push esi
+    mov ecx, 512 / 4
+    movzx esi, word ptr [dap_buffer_addr]
+    rep movsd [edi], [esi]
+    pop esi
+    pop ecx
+
+    # next block
+    mov eax, [dap_start_lba]
+    add eax, 1
+    mov [dap_start_lba], eax
+
+    sub ecx, 1
+    jnz load_next_module_block_from_disk
 
 video_mode_config:
     call vesa
@@ -103,6 +196,9 @@ enter_protected_mode_again:
 spin32:
     jmp spin32
 
+# physical address of the loaded module table, or 0 if there is none
+module_table_addr: .4byte 0
+
 
 
 # print a string and a newline
@@ -1,4 +1,5 @@
 #![feature(lang_items)]
+#![feature(maybe_uninit_slice)]
 #![no_std]
 #![no_main]
 
@@ -6,11 +7,18 @@
 compile_error!("The bootloader crate must be compiled for the `x86_64-bootloader.json` target");
 
 use bootloader::{
-    binary::SystemInfo,
+    binary::{
+        bios::module_table::ModuleTable,
+        compression,
+        legacy_memory_region::{LegacyFrameAllocator, LegacyMemoryRegion},
+        parsed_config::CONFIG,
+        SystemInfo,
+    },
     boot_info::{FrameBufferInfo, Module, PixelFormat},
 };
 use core::{
     arch::{asm, global_asm},
+    mem::{self, MaybeUninit},
     panic::PanicInfo,
     slice,
 };
@@ -21,6 +29,8 @@ use x86_64::structures::paging::{
 };
 use x86_64::{PhysAddr, VirtAddr};
 
+const PAGE_SIZE: u64 = 4096;
+
 global_asm!(include_str!("../asm/stage_1.s"));
 global_asm!(include_str!("../asm/stage_2.s"));
 global_asm!(include_str!(concat!(env!("OUT_DIR"), "/vesa_config.s")));
@@ -40,6 +50,11 @@ extern "C" {
     static VBEModeInfo_bluefieldposition: u8;
 }
 
+// values defined in `stage_2.s`
+extern "C" {
+    static module_table_addr: u32;
+}
+
 // Symbols defined in `linker.ld`
 extern "C" {
     static mmap_ent: usize;
@@ -61,12 +76,17 @@ pub unsafe extern "C" fn stage_4() -> ! {
     let kernel_size = &_kernel_size as *const _ as u64;
     let memory_map_addr = &_memory_map as *const _ as u64;
     let memory_map_entry_count = (mmap_ent & 0xff) as u64; // Extract lower 8 bits
+    let module_table = match module_table_addr {
+        0 => None, // no module table on disk
+        addr => ModuleTable::new(PhysAddr::new(addr.into())),
+    };
 
     bootloader_main(
         PhysAddr::new(kernel_start),
         kernel_size,
         VirtAddr::new(memory_map_addr),
         memory_map_entry_count,
+        module_table,
     )
 }
 
@@ -75,10 +95,9 @@ fn bootloader_main(
     kernel_size: u64,
     memory_map_addr: VirtAddr,
     memory_map_entry_count: u64,
+    module_table: Option<ModuleTable>,
 ) -> ! {
-    use bootloader::binary::{
-        bios::memory_descriptor::E820MemoryRegion, legacy_memory_region::LegacyFrameAllocator,
-    };
+    use bootloader::binary::{bios::memory_descriptor::E820MemoryRegion, multiboot2};
 
     let e820_memory_map = {
         let ptr = usize_from(memory_map_addr.as_u64()) as *const E820MemoryRegion;
@@ -90,12 +109,84 @@ fn bootloader_main(
         .max()
         .expect("no physical memory regions found");
 
+    // The second stage loads the modules directly behind the kernel. We place the module
+    // descriptors for the boot info behind them.
+    let (modules, loaded_end): (&'static mut [Module], PhysAddr) = match &module_table {
+        Some(table) => {
+            let addr = table.end().align_up(mem::align_of::<Module>() as u64);
+            let ptr = addr.as_u64() as *mut MaybeUninit<Module>;
+            let slots = unsafe { slice::from_raw_parts_mut(ptr, table.len()) };
+            for (slot, module) in slots.iter_mut().zip(table.modules()) {
+                slot.write(module);
+            }
+            let modules = unsafe { MaybeUninit::slice_assume_init_mut(slots) };
+            let end = addr + mem::size_of_val(modules);
+            (modules, end)
+        }
+        None => (&mut [], kernel_start + kernel_size),
+    };
+
+    let kernel = {
+        let ptr = kernel_start.as_u64() as *const u8;
+        unsafe { slice::from_raw_parts(ptr, usize_from(kernel_size)) }
+    };
+
     let mut frame_allocator = {
-        let kernel_end = PhysFrame::containing_address(kernel_start + kernel_size - 1u64);
-        let next_free = kernel_end + 1;
+        let loaded_end = PhysFrame::containing_address(loaded_end - 1u64);
+        let next_free = loaded_end + 1;
         LegacyFrameAllocator::new_starting_at(next_free, e820_memory_map.iter().copied())
     };
 
+    // Compressed payloads are decompressed into frames allocated from the memory map. Modules
+    // that don't fulfill their placement constraints are copied into such frames too. The
+    // frames are allocated first, so that they are adjacent to the loaded modules, but they
+    // are only written after identity-mapping all memory.
+    const MODULE_COUNT: usize = CONFIG.modules.len();
+    let mut module_sources: [Option<&'static [u8]>; MODULE_COUNT] = [None; MODULE_COUNT];
+    for ((module, config), source) in modules
+        .iter_mut()
+        .zip(CONFIG.modules)
+        .zip(&mut module_sources)
+    {
+        let align = config.align.unwrap_or(PAGE_SIZE);
+        let data = unsafe { slice::from_raw_parts(module.phys_addr as *const u8, module.len) };
+        let fits = |addr: u64, len: usize| {
+            addr % align == 0
+                && config
+                    .max_phys_addr
+                    .map_or(true, |max| addr + len as u64 <= max)
+        };
+        let target_len = match CONFIG.compression {
+            Some(compression) => Some(compression::uncompressed_size(compression, data)),
+            None if !fits(module.phys_addr, module.len) => Some(module.len),
+            None => None,
+        };
+        if let Some(len) = target_len {
+            module.phys_addr = allocate(&mut frame_allocator, len, align, &module.name);
+            module.len = len;
+            *source = Some(data);
+        }
+        if !fits(module.phys_addr, module.len) {
+            panic!(
+                "failed to place module `{}` according to its `align` and `max-phys-addr` keys",
+                &*module.name
+            );
+        }
+    }
+    let module_region = modules
+        .iter()
+        .map(|m| (m.phys_addr, m.phys_addr + m.len as u64))
+        .reduce(|(start, end), (s, e)| (start.min(s), end.max(e)));
+    if let Some((start, end)) = module_region {
+        frame_allocator.set_module_region(PhysAddr::new(start), PhysAddr::new(end));
+    }
+
+    let kernel_target = CONFIG.compression.map(|compression| {
+        let len = compression::uncompressed_size(compression, kernel);
+        let addr = allocate(&mut frame_allocator, len, PAGE_SIZE, "kernel");
+        unsafe { slice::from_raw_parts_mut(addr as *mut u8, len) }
+    });
+
     // We identity-map all memory, so the offset between physical and virtual addresses is 0
     let phys_offset = VirtAddr::new(0);
 
@@ -123,6 +214,36 @@ fn bootloader_main(
         }
     }
 
+    for (module, source) in modules.iter().zip(module_sources) {
+        if let Some(data) = source {
+            let target =
+                unsafe { slice::from_raw_parts_mut(module.phys_addr as *mut u8, module.len) };
+            match CONFIG.compression {
+                Some(compression) => compression::decompress_into(compression, data, target),
+                None => target.copy_from_slice(data),
+            }
+        }
+    }
+
+    let kernel: &'static [u8] = match (CONFIG.compression, kernel_target) {
+        (Some(compression), Some(target)) => {
+            compression::decompress_into(compression, kernel, target);
+            target
+        }
+        _ => kernel,
+    };
+
+    // Multiboot2 kernels are copied to fixed physical addresses, which must not be used by the
+    // frame allocator
+    if let Some(multiboot2_kernel) = multiboot2::detect(kernel) {
+        let kernel_end = multiboot2::check_kernel_memory(
+            &multiboot2_kernel,
+            e820_memory_map.iter().copied(),
+            (kernel_start, frame_allocator.next_free_addr()),
+        );
+        frame_allocator.skip_to(kernel_end);
+    }
+
     let framebuffer_addr = PhysAddr::new(unsafe { VBEModeInfo_physbaseptr }.into());
     let mut error = None;
     let framebuffer_info = unsafe {
@@ -152,6 +273,9 @@ fn bootloader_main(
     };
 
     log::info!("BIOS boot");
+    if !modules.is_empty() {
+        log::info!("Loaded {} modules from disk", modules.len());
+    }
 
     if let Some((msg, r, g, b)) = error {
         panic!("{}: r: {}, g: {}, b: {}", msg, r, g, b);
@@ -159,11 +283,6 @@ fn bootloader_main(
 
     let page_tables = create_page_tables(&mut frame_allocator);
 
-    let kernel = {
-        let ptr = kernel_start.as_u64() as *const u8;
-        unsafe { slice::from_raw_parts(ptr, usize_from(kernel_size)) }
-    };
-
     let system_info = SystemInfo {
         framebuffer_addr,
         framebuffer_info,
@@ -175,13 +294,33 @@ fn bootloader_main(
         frame_allocator,
         page_tables,
         system_info,
-        unsafe {
-            static mut EMPTY: [Module; 0] = [];
-            (&mut EMPTY[..]).into()
-        },
+        modules.into(),
     );
 }
 
+/// Allocates physically contiguous frames for `len` bytes at an `align`-aligned address and
+/// returns their start address.
+///
+/// The frames are allocated from the usable regions of the memory map, so they might not be
+/// identity-mapped yet.
+fn allocate<I, D>(
+    frame_allocator: &mut LegacyFrameAllocator<I, D>,
+    len: usize,
+    align: u64,
+    name: &str,
+) -> u64
+where
+    I: ExactSizeIterator<Item = D> + Clone,
+    D: LegacyMemoryRegion,
+{
+    let frames = (len as u64).div_ceil(PAGE_SIZE).max(1);
+    frame_allocator
+        .allocate_frames(frames, align.max(PAGE_SIZE))
+        .unwrap_or_else(|| panic!("failed to allocate {} bytes of memory for `{}`", len, name))
+        .start_address()
+        .as_u64()
+}
+
 fn init_logger(
     framebuffer_start: PhysAddr,
     framebuffer_size: usize,
@@ -1,6 +1,12 @@
 use anyhow::{anyhow, bail, Context};
 use argh::FromArgs;
-use bootloader::{disk_image::create_disk_image, ModuleEntry};
+use bootloader::{
+    disk_image::{append_bios_modules, create_disk_image},
+    kernel_check::{check_kernel, LoadKernelError},
+    linux::BzImage,
+    multiboot2::Multiboot2Kernel,
+    ModuleEntry,
+};
 use std::{
     convert::TryFrom,
     env,
@@ -10,15 +16,17 @@ use std::{
     process::Command,
     str::FromStr,
 };
+use xmas_elf::{header, program::Type, ElfFile};
 
 type ExitCode = i32;
 
 #[derive(FromArgs)]
 /// Build the bootloader
 struct BuildArguments {
-    /// path to the `Cargo.toml` of the kernel
+    /// path to the `Cargo.toml` of the kernel (optional if the kernel embeds its config through
+    /// `bootloader::config!`)
     #[argh(option)]
-    kernel_manifest: PathBuf,
+    kernel_manifest: Option<PathBuf>,
 
     /// path to the kernel ELF binary
     #[argh(option)]
@@ -109,7 +117,9 @@ fn main() -> anyhow::Result<()> {
             cmd.arg("--quiet");
         }
         cmd.env("KERNEL", &args.kernel_binary);
-        cmd.env("KERNEL_MANIFEST", &args.kernel_manifest);
+        if let Some(kernel_manifest) = &args.kernel_manifest {
+            cmd.env("KERNEL_MANIFEST", kernel_manifest);
+        }
         assert!(cmd.status()?.success());
 
         // Retrieve binary paths
@@ -154,46 +164,9 @@ fn main() -> anyhow::Result<()> {
                 )
             })?;
 
-        // Get module information
-        let out_dir = output
-            .lines()
-            .find_map(|message| {
-                let message = json::parse(message).unwrap();
-                if message["reason"].as_str() == Some("build-script-executed")
-                    && message["package_id"].as_str() == Some(&executable_package_id)
-                {
-                    Some(String::from(message["out_dir"].as_str().unwrap()))
-                } else {
-                    None
-                }
-            })
-            .unwrap();
-        let module_json_path = PathBuf::from(out_dir).join("module_config.json");
-        let mut module_json_str = String::new();
-        File::open(module_json_path)
-            .expect("could not open module_config.json")
-            .read_to_string(&mut module_json_str)?;
-        let module_json = json::parse(&module_json_str).unwrap();
-        assert!(module_json.is_array());
-        let module_json = match module_json {
-            json::JsonValue::Array(arr) => arr,
-            _ => unreachable!(),
-        };
-        let modules = {
-            let mut modules = vec![];
-            for module in module_json {
-                let name = {
-                    let mut buf = [0u8; 32];
-                    let name = module["name"].as_str().unwrap().as_bytes();
-                    buf[..name.len()].copy_from_slice(name);
-                    buf
-                };
-                let path =
-                    Box::leak(String::from(module["path"].as_str().unwrap()).into_boxed_str());
-                modules.push(ModuleEntry { name, path });
-            }
-            modules
-        };
+        let out_dir = build_script_out_dir(&output, &executable_package_id)?;
+        preflight_check(&args.kernel_binary, &out_dir)?;
+        let modules = read_module_config(&out_dir)?;
 
         if let Some(out_dir) = &args.out_dir {
             let efi_file = out_dir.join(format!("boot-{}-{}.efi", executable_name, kernel_name));
@@ -219,7 +192,9 @@ fn main() -> anyhow::Result<()> {
             cmd.arg("--quiet");
         }
         cmd.env("KERNEL", &args.kernel_binary);
-        cmd.env("KERNEL_MANIFEST", &args.kernel_manifest);
+        if let Some(kernel_manifest) = &args.kernel_manifest {
+            cmd.env("KERNEL_MANIFEST", kernel_manifest);
+        }
         cmd.env("RUSTFLAGS", "-C opt-level=s");
         assert!(cmd.status()?.success());
 
@@ -232,18 +207,18 @@ fn main() -> anyhow::Result<()> {
             return Err(anyhow!("{}", String::from_utf8_lossy(&output.stderr)));
         }
         let mut executables = Vec::new();
-        for line in String::from_utf8(output.stdout)
-            .context("build JSON output is not valid UTF-8")?
-            .lines()
-        {
+        let output =
+            String::from_utf8(output.stdout).context("build JSON output is not valid UTF-8")?;
+        for line in output.lines() {
             let mut artifact = json::parse(line).context("build JSON output is not valid JSON")?;
             if let Some(executable) = artifact["executable"].take_string() {
-                executables.push(PathBuf::from(executable));
+                let package_id = artifact["package_id"].take_string().unwrap();
+                executables.push((PathBuf::from(executable), package_id));
             }
         }
 
         assert_eq!(executables.len(), 1);
-        let executable_path = executables.pop().unwrap();
+        let (executable_path, executable_package_id) = executables.pop().unwrap();
         let executable_name = executable_path.file_name().unwrap().to_str().unwrap();
         let kernel_name = args.kernel_binary.file_name().unwrap().to_str().unwrap();
         let mut output_bin_path = executable_path
@@ -251,8 +226,14 @@ fn main() -> anyhow::Result<()> {
             .unwrap()
             .join(format!("boot-{}-{}.img", executable_name, kernel_name));
 
+        let out_dir = build_script_out_dir(&output, &executable_package_id)?;
+        preflight_check(&args.kernel_binary, &out_dir)?;
+
         create_disk_image(&executable_path, &output_bin_path)
             .context("Failed to create bootable disk image")?;
+        let modules = read_module_config(&out_dir)?;
+        append_bios_modules(&executable_path, &output_bin_path, &modules)
+            .context("Failed to append modules to bootable disk image")?;
 
         if let Some(out_dir) = &args.out_dir {
             let file = out_dir.join(output_bin_path.file_name().unwrap());
@@ -275,6 +256,307 @@ fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Returns the `OUT_DIR` of the build script of the given package.
+///
+/// The `build_output` argument must be the JSON output of the cargo build command.
+fn build_script_out_dir(build_output: &str, package_id: &str) -> anyhow::Result<PathBuf> {
+    build_output
+        .lines()
+        .find_map(|message| {
+            let message = json::parse(message).unwrap();
+            if message["reason"].as_str() == Some("build-script-executed")
+                && message["package_id"].as_str() == Some(package_id)
+            {
+                Some(String::from(message["out_dir"].as_str().unwrap()))
+            } else {
+                None
+            }
+        })
+        .map(PathBuf::from)
+        .ok_or_else(|| anyhow!("build script output directory not found"))
+}
+
+/// Reads the `module_config.json` file that the build script wrote to the given `OUT_DIR`.
+fn read_module_config(out_dir: &Path) -> anyhow::Result<Vec<ModuleEntry>> {
+    let module_json_path = out_dir.join("module_config.json");
+    let mut module_json_str = String::new();
+    File::open(module_json_path)
+        .context("could not open module_config.json")?
+        .read_to_string(&mut module_json_str)?;
+    let module_json = match json::parse(&module_json_str)? {
+        json::JsonValue::Array(arr) => arr,
+        _ => bail!("module_config.json does not contain an array"),
+    };
+
+    let mut modules = vec![];
+    for module in module_json {
+        let name = Box::leak(String::from(module["name"].as_str().unwrap()).into_boxed_str());
+        let path = Box::leak(String::from(module["path"].as_str().unwrap()).into_boxed_str());
+        let args = Box::leak(String::from(module["args"].as_str().unwrap_or("")).into_boxed_str());
+        let sha256_hex = module["sha256"].as_str().unwrap_or("");
+        let mut sha256 = [0; 32];
+        if sha256_hex.len() != 64 {
+            bail!("invalid SHA-256 digest for module `{}`", name);
+        }
+        for (i, byte) in sha256.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&sha256_hex[i * 2..i * 2 + 2], 16)
+                .with_context(|| format!("invalid SHA-256 digest for module `{}`", name))?;
+        }
+        modules.push(ModuleEntry {
+            name,
+            path,
+            args,
+            sha256,
+            // only needed by the bootloader itself
+            align: None,
+            max_phys_addr: None,
+        });
+    }
+    Ok(modules)
+}
+
+/// Checks that the kernel can be loaded by the bootloader before creating a disk image.
+///
+/// Runs the checks of [`check_kernel`] on the host and cross-checks the kernel against the
+/// `preflight_config.json` file that the build script wrote to the given `OUT_DIR`. This way,
+/// broken kernels are reported with a hint on how to fix them instead of failing at boot time.
+fn preflight_check(kernel_binary: &Path, out_dir: &Path) -> anyhow::Result<()> {
+    let mut config_str = String::new();
+    File::open(out_dir.join("preflight_config.json"))
+        .context("could not open preflight_config.json")?
+        .read_to_string(&mut config_str)?;
+    let config = json::parse(&config_str).context("preflight_config.json is not valid JSON")?;
+
+    let kernel = fs::read(kernel_binary)
+        .with_context(|| format!("failed to read kernel binary `{}`", kernel_binary.display()))?;
+    let fail = |message: String, hint: &str| {
+        anyhow!(
+            "kernel binary `{}` can't be loaded by the bootloader: {}\n\n{}",
+            kernel_binary.display(),
+            message,
+            hint
+        )
+    };
+
+    // Multiboot2 kernels are copied to fixed physical addresses instead of being mapped, so
+    // the virtual memory checks below don't apply to them
+    if let Some(result) = Multiboot2Kernel::parse(&kernel) {
+        return result.map(|_| ()).map_err(|err| {
+            let hint = match err {
+                LoadKernelError::UnsupportedMultiboot2Tag { .. } => {
+                    "Remove the tag from the Multiboot2 header or mark it as optional."
+                }
+                LoadKernelError::InvalidPhysicalAddress { .. } => {
+                    "Link the kernel to physical addresses between 1MiB and 4GiB, e.g. through \
+                    the `AT` keyword in the linker script."
+                }
+                _ => "Check the Multiboot2 header and the linker script of the kernel.",
+            };
+            fail(err.to_string(), hint)
+        });
+    }
+
+    // Linux kernel images are no ELF files and are copied to an unused physical address
+    if let Some(result) = BzImage::parse(&kernel) {
+        let kernel_cmdline = config["kernel-cmdline"].as_str().unwrap_or("");
+        return result
+            .and_then(|bzimage| bzimage.check_cmdline(kernel_cmdline))
+            .map_err(|err| {
+                let hint = match err {
+                    LoadKernelError::UnsupportedBzImage(_) => {
+                        "Build a 64-bit kernel with the `CONFIG_RELOCATABLE` option enabled."
+                    }
+                    LoadKernelError::KernelCmdlineTooLong { .. } => {
+                        "Shorten the `kernel-cmdline` config option."
+                    }
+                    _ => {
+                        "The `--kernel-binary` argument must point to the `bzImage` of the kernel."
+                    }
+                };
+                fail(err.to_string(), hint)
+            });
+    }
+
+    let elf_file = ElfFile::new(&kernel).map_err(|err| {
+        fail(
+            format!("invalid ELF file: {}", err),
+            "The `--kernel-binary` argument must point to the compiled kernel executable.",
+        )
+    })?;
+    if elf_file.header.pt2.entry_point() == 0 {
+        return Err(fail(
+            "the kernel has no entry point".into(),
+            "Define the entry point of the kernel using the `bootloader::entry_point!` macro.",
+        ));
+    }
+    let strict_wx = config["strict-wx"].as_bool().unwrap_or(false);
+    check_kernel(&elf_file, strict_wx).map_err(|err| {
+        let hint = match err {
+            LoadKernelError::WrongMachine => {
+                "Compile the kernel for an x86_64 target, e.g. `x86_64-unknown-none`."
+            }
+            LoadKernelError::WrongType => "Compile the kernel as an executable, not as a library.",
+            LoadKernelError::EntryPointNotExecutable { .. } => {
+                "Define the entry point of the kernel using the `bootloader::entry_point!` macro."
+            }
+            LoadKernelError::WritableAndExecutable { .. } => {
+                "Place code and writable data in separate segments (e.g. by not passing `-N` to \
+                the linker) or disable the `strict-wx` config option."
+            }
+            _ => "Check the linker arguments and linker script of the kernel.",
+        };
+        fail(err.to_string(), hint)
+    })?;
+
+    // the segments of position-independent kernels are placed at an unused address at boot time
+    if matches!(
+        elf_file.header.pt2.type_().as_type(),
+        header::Type::SharedObject
+    ) {
+        return Ok(());
+    }
+    let segments: Vec<_> = elf_file
+        .program_iter()
+        .filter(|segment| matches!(segment.get_type(), Ok(Type::Load)))
+        .map(|segment| {
+            let start = align_down(segment.virtual_addr(), PAGE_SIZE);
+            let end = align_up(segment.virtual_addr() + segment.mem_size(), PAGE_SIZE);
+            (start, end)
+        })
+        .collect();
+
+    for &(start, _) in &segments {
+        if start < LOW_MEMORY_END {
+            return Err(fail(
+                format!(
+                    "the kernel segment at {:#x} is in the first MiB of memory, which is used \
+                    by the BIOS and the bootloader",
+                    start
+                ),
+                "Link the kernel at a higher address (e.g. `-C link-arg=--image-base=0x200000`) \
+                or compile it as a position-independent executable.",
+            ));
+        }
+    }
+    // The bootloader identity-maps its context switch code and the GDT in the kernel's address
+    // space. These frames are only known at boot time (e.g. the UEFI firmware chooses where the
+    // bootloader is loaded), so an overlap with a kernel segment in the first level 4 entry
+    // can't be ruled out here.
+    if let Some(&(start, _)) = segments
+        .iter()
+        .find(|&&(start, _)| start < LEVEL_4_ENTRY_SIZE)
+    {
+        eprintln!(
+            "warning: the kernel segment at {:#x} is in the first 512GiB of the address space, \
+            where the bootloader identity-maps some of its frames; if loading the kernel fails, \
+            link it to a higher half address or compile it as a position-independent executable",
+            start
+        );
+    }
+
+    let config_location = match config["config-source"].as_str() {
+        Some("embedded") => "the `bootloader::config!` invocation of the kernel",
+        _ => "the `[package.metadata.bootloader]` table of the kernel manifest",
+    };
+    for (name, start, len) in config_regions(&config) {
+        let end = start.saturating_add(len);
+        if let Some(&(segment_start, _)) = segments
+            .iter()
+            .find(|&&(segment_start, segment_end)| start < segment_end && segment_start < end)
+        {
+            return Err(fail(
+                format!(
+                    "the memory region {:#x}..{:#x} of the `{}` config option overlaps the \
+                    kernel segment at {:#x}",
+                    start, end, name, segment_start
+                ),
+                &format!(
+                    "Choose a different address for `{}` in {} or remove it to let the \
+                    bootloader choose an unused address.{}",
+                    name,
+                    config_location,
+                    if name != "kernel-stack-address" && name != "recursive-index" {
+                        " Note that only the first page of this region is checked, since its \
+                        size is only known at boot time."
+                    } else {
+                        ""
+                    }
+                ),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+const PAGE_SIZE: u64 = 4096;
+
+/// End address of the first MiB of memory, which is used by the BIOS and the BIOS bootloader.
+const LOW_MEMORY_END: u64 = 0x10_0000;
+
+/// Size of the virtual memory region that is covered by a single level 4 page table entry.
+const LEVEL_4_ENTRY_SIZE: u64 = 1 << 39;
+
+/// Returns the virtual memory regions that are reserved by the given preflight config.
+///
+/// The regions are returned as `(option name, start address, length)` tuples. The size of
+/// the boot info, the framebuffer, the modules, and the physical memory mapping is only known
+/// at boot time, so only their first page is included.
+fn config_regions(config: &json::JsonValue) -> Vec<(&'static str, u64, u64)> {
+    let mut regions = Vec::new();
+    if let Some(address) = config["kernel-stack-address"].as_u64() {
+        let size = config["kernel-stack-size"]
+            .as_u64()
+            .unwrap_or(20 * PAGE_SIZE);
+        // the guard region below the stack must not be mapped either
+        let guard_size = config["kernel-stack-guard-size"]
+            .as_u64()
+            .unwrap_or(PAGE_SIZE)
+            .min(address);
+        regions.push((
+            "kernel-stack-address",
+            address - guard_size,
+            guard_size + size,
+        ));
+    }
+    for &name in &[
+        "boot-info-address",
+        "framebuffer-address",
+        "modules-address",
+    ] {
+        if let Some(address) = config[name].as_u64() {
+            regions.push((name, address, PAGE_SIZE));
+        }
+    }
+    if config["map-physical-memory"].as_bool() == Some(true) {
+        if let Some(offset) = config["physical-memory-offset"].as_u64() {
+            regions.push(("physical-memory-offset", offset, PAGE_SIZE));
+        }
+    }
+    if config["map-page-table-recursively"].as_bool() == Some(true) {
+        if let Some(index) = config["recursive-index"].as_u64() {
+            // the recursive entry covers a complete level 4 entry
+            let start = index << 39;
+            // sign extension for canonical higher half addresses
+            let start = if index >= 256 {
+                start | 0xffff_0000_0000_0000
+            } else {
+                start
+            };
+            regions.push(("recursive-index", start, 1 << 39));
+        }
+    }
+    regions
+}
+
+fn align_down(addr: u64, align: u64) -> u64 {
+    addr / align * align
+}
+
+fn align_up(addr: u64, align: u64) -> u64 {
+    align_down(addr + align - 1, align)
+}
+
 fn create_uefi_disk_image(
     executable_path: &Path,
     efi_file: &Path,
@@ -318,13 +600,13 @@ fn create_uefi_disk_image(
         bootx64.truncate()?;
         io::copy(&mut fs::File::open(&executable_path)?, &mut bootx64)?;
 
-        // copy modules to FAT filesystem
+        // copy modules to FAT filesystem, creating subdirectories for nested module names
         for module in modules {
-            let path = String::from("efi/boot/")
-                + core::str::from_utf8(&module.name)
-                    .unwrap()
-                    .trim_end_matches('\0');
-            let mut file = root_dir.create_file(&path)?;
+            let components: Vec<_> = module.name.split('/').collect();
+            for i in 1..components.len() {
+                root_dir.create_dir(&format!("efi/boot/{}", components[..i].join("/")))?;
+            }
+            let mut file = root_dir.create_file(&format!("efi/boot/{}", module.name))?;
             file.truncate()?;
             io::copy(
                 &mut fs::File::open(module.path).context("Failed to read module file")?,
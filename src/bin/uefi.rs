@@ -13,9 +13,17 @@ static KERNEL: PageAligned<[u8; KERNEL_SIZE]> = PageAligned(KERNEL_BYTES);
 #[repr(align(4096))]
 struct PageAligned<T>(T);
 
+const PAGE_SIZE: u64 = 4096;
+
 use bootloader::{
-    binary::{legacy_memory_region::LegacyFrameAllocator, parsed_config::CONFIG, SystemInfo},
+    binary::{
+        compression, kernel_load_failed, legacy_memory_region::LegacyFrameAllocator, multiboot2,
+        parsed_config::CONFIG, uefi::MODULE_MEMORY_TYPE, SystemInfo,
+    },
     boot_info::{FrameBufferInfo, Module},
+    kernel_check::LoadKernelError,
+    multiboot2::Multiboot2Kernel,
+    Compression,
 };
 use core::{
     mem::{self, MaybeUninit},
@@ -29,7 +37,7 @@ use uefi::{
         console::gop::{GraphicsOutput, PixelFormat},
         media::file::{Directory, File, FileAttribute, FileInfo, FileMode, FileType, RegularFile},
     },
-    table::boot::{MemoryDescriptor, MemoryType},
+    table::boot::{AllocateType, MemoryDescriptor, MemoryType},
     Completion, Result,
 };
 use x86_64::{
@@ -99,24 +107,63 @@ fn efi_main(image: Handle, st: SystemTable<Boot>) -> Status {
     };
 
     for (i, module) in CONFIG.modules.iter().enumerate() {
-        let name = module.name;
-        let name_str = core::str::from_utf8(&module.name)
-            .unwrap()
-            .trim_end_matches('\0');
-        let file = boot_dir
-            .open(name_str, FileMode::Read, FileAttribute::empty())
-            .unwrap_success();
-        if let FileType::Regular(mut file) = file.into_type().unwrap_success() {
-            let data = read_file(&st, &mut file).unwrap_success();
-            modules[i].write(Module {
-                name,
-                phys_addr: data.as_ptr() as u64,
-                len: data.len(),
-            });
-        }
+        let mut file = open_module_file(&mut boot_dir, module.name);
+        // allocates memory for the module contents, honoring its placement constraints
+        let allocate_module = |len| {
+            allocate_pages(
+                &st,
+                MODULE_MEMORY_TYPE,
+                len,
+                module.align.unwrap_or(PAGE_SIZE),
+                module.max_phys_addr,
+            )
+        };
+        let data: &'static [u8] = match CONFIG.compression {
+            None => read_file(&st, &mut file, allocate_module).unwrap_success(),
+            Some(compression) => {
+                let compressed = read_file(&st, &mut file, |len| {
+                    let ptr = st
+                        .boot_services()
+                        .allocate_pool(MemoryType::LOADER_DATA, len)
+                        .unwrap_success();
+                    unsafe { slice::from_raw_parts_mut(ptr, len) }
+                })
+                .unwrap_success();
+                let data = allocate_module(compression::uncompressed_size(compression, compressed));
+                compression::decompress_into(compression, compressed, data);
+                st.boot_services()
+                    .free_pool(compressed.as_ptr() as *mut u8)
+                    .unwrap_success();
+                data
+            }
+        };
+        modules[i].write(Module {
+            name: module.name.into(),
+            phys_addr: data.as_ptr() as u64,
+            virt_addr: 0, // set when the module is mapped
+            len: data.len(),
+            args: module.args.into(),
+            sha256: module.sha256,
+        });
     }
     let modules = unsafe { MaybeUninit::slice_assume_init_mut(modules) };
 
+    let kernel: &'static [u8] = match CONFIG.compression {
+        None => &KERNEL.0,
+        Some(compression) => {
+            let len = compression::uncompressed_size(compression, &KERNEL.0);
+            let kernel = allocate_pages(&st, MemoryType::LOADER_DATA, len, PAGE_SIZE, None);
+            compression::decompress_into(compression, &KERNEL.0, kernel);
+            kernel
+        }
+    };
+
+    // Multiboot2 kernels are copied to fixed physical addresses, so we allocate them before
+    // exiting the boot services to prevent them from being used otherwise
+    if let Some(multiboot2_kernel) = multiboot2::detect(kernel) {
+        allocate_multiboot2_memory(&st, &multiboot2_kernel);
+    }
+
     log::trace!("exiting boot services");
     let (system_table, memory_map) = st
         .exit_boot_services(image, mmap_storage)
@@ -142,7 +189,7 @@ fn efi_main(image: Handle, st: SystemTable<Boot>) -> Status {
     };
 
     bootloader::binary::load_and_switch_to_kernel(
-        &KERNEL.0,
+        kernel,
         frame_allocator,
         page_tables,
         system_info,
@@ -296,14 +343,47 @@ fn file_info(st: &SystemTable<Boot>, file: &mut RegularFile) -> Result<&'static
     })
 }
 
-fn read_file(st: &SystemTable<Boot>, file: &mut RegularFile) -> Result<&'static [u8]> {
+/// Opens the file of the module with the given name in the `efi/boot` directory.
+///
+/// Module names may contain `/`-separated subdirectories, which are opened one by one.
+fn open_module_file(boot_dir: &mut Directory, name: &str) -> RegularFile {
+    let mut components = name.split('/');
+    let file_name = components.next_back().unwrap();
+
+    let mut subdir: Option<Directory> = None;
+    for component in components {
+        let dir = subdir.as_mut().unwrap_or(&mut *boot_dir);
+        let handle = dir
+            .open(component, FileMode::Read, FileAttribute::empty())
+            .unwrap_success();
+        subdir = match handle.into_type().unwrap_success() {
+            FileType::Dir(dir) => Some(dir),
+            _ => panic!(
+                "module directory `{}` of `{}` is not a directory",
+                component, name
+            ),
+        };
+    }
+
+    let dir = subdir.as_mut().unwrap_or(boot_dir);
+    let handle = dir
+        .open(file_name, FileMode::Read, FileAttribute::empty())
+        .unwrap_success();
+    match handle.into_type().unwrap_success() {
+        FileType::Regular(file) => file,
+        _ => panic!("module `{}` is not a regular file", name),
+    }
+}
+
+/// Reads the complete file into a buffer of file size that is returned by `allocate`.
+fn read_file(
+    st: &SystemTable<Boot>,
+    file: &mut RegularFile,
+    allocate: impl FnOnce(usize) -> &'static mut [u8],
+) -> Result<&'static [u8]> {
     let file_info = file_info(st, file)?.log();
     let len = file_info.file_size() as usize;
-    let bufptr = st
-        .boot_services()
-        .allocate_pool(MemoryType::LOADER_DATA, len)?
-        .log();
-    let buffer = unsafe { core::slice::from_raw_parts_mut(bufptr, len) };
+    let buffer = allocate(len);
     file.read(buffer).discard_errdata()?.log();
     assert!(
         file.read(&mut [0u8; 8]).discard_errdata()?.log() == 0,
@@ -313,6 +393,76 @@ fn read_file(st: &SystemTable<Boot>, file: &mut RegularFile) -> Result<&'static
     Ok(Completion::new(Status::SUCCESS, &buffer[..]))
 }
 
+/// Allocates pages of the given memory type for `len` bytes.
+///
+/// Module contents are allocated as [`MODULE_MEMORY_TYPE`], so that the memory is reported as
+/// [`MemoryRegionKind::Module`] to the kernel.
+///
+/// The start address is aligned to `align`, which must be a power of two. If `max_phys_addr` is
+/// given, the allocated memory ends at or below this address. To fulfill alignments larger
+/// than a page, additional pages are allocated and the unused ones are freed afterwards.
+///
+/// [`MemoryRegionKind::Module`]: bootloader::boot_info::MemoryRegionKind::Module
+fn allocate_pages(
+    st: &SystemTable<Boot>,
+    memory_type: MemoryType,
+    len: usize,
+    align: u64,
+    max_phys_addr: Option<u64>,
+) -> &'static mut [u8] {
+    let align = align.max(PAGE_SIZE);
+    let pages = ((len as u64 + PAGE_SIZE - 1) / PAGE_SIZE).max(1);
+    let allocated_pages = pages + align / PAGE_SIZE - 1;
+    let ty = match max_phys_addr {
+        // the address of the last allocated byte must not be larger than this
+        Some(max) => {
+            let max = max.checked_sub(1).expect("`max-phys-addr` must not be zero");
+            AllocateType::MaxAddress(max as usize)
+        }
+        None => AllocateType::AnyPages,
+    };
+
+    let boot_services = st.boot_services();
+    let allocation_start = boot_services
+        .allocate_pages(ty, memory_type, allocated_pages as usize)
+        .expect_success("failed to allocate pages");
+    let allocation_end = allocation_start + allocated_pages * PAGE_SIZE;
+    let start = (allocation_start + align - 1) & !(align - 1);
+    let end = start + pages * PAGE_SIZE;
+    if start > allocation_start {
+        let count = (start - allocation_start) / PAGE_SIZE;
+        boot_services
+            .free_pages(allocation_start, count as usize)
+            .expect_success("failed to free pages");
+    }
+    if allocation_end > end {
+        let count = (allocation_end - end) / PAGE_SIZE;
+        boot_services
+            .free_pages(end, count as usize)
+            .expect_success("failed to free pages");
+    }
+
+    unsafe { slice::from_raw_parts_mut(start as *mut u8, len) }
+}
+
+/// Allocates the physical memory that the segments of the given Multiboot2 kernel are copied to.
+///
+/// The memory is allocated as `LOADER_DATA`, so the frame allocator does not use it after
+/// exiting the boot services.
+fn allocate_multiboot2_memory(st: &SystemTable<Boot>, kernel: &Multiboot2Kernel) {
+    let (start, end) = kernel.load_range();
+    let start = start / PAGE_SIZE * PAGE_SIZE;
+    let pages = (end - start + PAGE_SIZE - 1) / PAGE_SIZE;
+    let result = st.boot_services().allocate_pages(
+        AllocateType::Address(start as usize),
+        MemoryType::LOADER_DATA,
+        pages as usize,
+    );
+    if result.is_err() {
+        kernel_load_failed(LoadKernelError::PhysicalAddressInUse { addr: start });
+    }
+}
+
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     unsafe {
@@ -1,2 +1,4 @@
 /// Provides an abstraction type for a BIOS-provided memory region.
 pub mod memory_descriptor;
+/// Provides access to the module table that the builder appends to BIOS disk images.
+pub mod module_table;
@@ -0,0 +1,99 @@
+use crate::{binary::parsed_config::CONFIG, boot_info::Module};
+use core::{mem, slice};
+use x86_64::PhysAddr;
+
+/// The magic number at the start of every module table.
+pub const MAGIC: [u8; 8] = *b"BLMODTAB";
+
+/// The header of the module table that the builder appends to BIOS disk images.
+///
+/// The table consists of this header, followed by `count` [`ModuleTableEntry`] structs. The
+/// module contents follow the table. The layout must be always identical with the one written
+/// by `append_bios_modules` in `src/disk_image.rs`.
+#[doc(hidden)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct ModuleTableHeader {
+    pub magic: [u8; 8],
+    /// Size of the table and all module contents, in 512-byte disk blocks.
+    pub total_blocks: u32,
+    pub count: u32,
+}
+
+/// Describes a single module in the module table.
+#[doc(hidden)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct ModuleTableEntry {
+    /// Offset of the module contents from the start of the module table, in bytes.
+    pub offset: u64,
+    pub len: u64,
+}
+
+/// A module table that was loaded into memory by the second bootloader stage.
+pub struct ModuleTable {
+    start: PhysAddr,
+    header: &'static ModuleTableHeader,
+}
+
+impl ModuleTable {
+    /// Creates a new instance from the module table at the given identity-mapped address.
+    ///
+    /// Returns `None` if there is no valid module table at this address.
+    ///
+    /// ## Safety
+    ///
+    /// The given address must point to memory that was loaded from disk by the second stage.
+    pub unsafe fn new(start: PhysAddr) -> Option<Self> {
+        let header: &'static ModuleTableHeader =
+            unsafe { &*(start.as_u64() as *const ModuleTableHeader) };
+        if header.magic == MAGIC {
+            Some(Self { start, header })
+        } else {
+            None
+        }
+    }
+
+    /// Returns the physical start address of the module table.
+    pub fn start(&self) -> PhysAddr {
+        self.start
+    }
+
+    /// Returns the physical address directly behind the last loaded module.
+    pub fn end(&self) -> PhysAddr {
+        self.start + u64::from(self.header.total_blocks) * 512
+    }
+
+    /// Returns the number of modules in the table.
+    pub fn len(&self) -> usize {
+        self.header.count as usize
+    }
+
+    /// Returns the raw entries of the module table.
+    pub fn entries(&self) -> &'static [ModuleTableEntry] {
+        let entries_addr = self.start + mem::size_of::<ModuleTableHeader>();
+        let ptr = entries_addr.as_u64() as *const ModuleTableEntry;
+        unsafe { slice::from_raw_parts(ptr, self.len()) }
+    }
+
+    /// Creates a boot info [`Module`] for each module in the table.
+    ///
+    /// The builder writes the table in the order of the configured modules, so the module
+    /// names and arguments are taken from the corresponding [`CONFIG`] entries.
+    pub fn modules(&self) -> impl Iterator<Item = Module> + '_ {
+        self.entries().iter().enumerate().map(move |(i, entry)| {
+            let config = CONFIG
+                .modules
+                .get(i)
+                .expect("module table does not match module config");
+            Module {
+                name: config.name.into(),
+                phys_addr: (self.start + entry.offset).as_u64(),
+                virt_addr: 0, // set when the module is mapped
+                len: entry.len as usize,
+                args: config.args.into(),
+                sha256: config.sha256,
+            }
+        })
+    }
+}
@@ -0,0 +1,100 @@
+use crate::config::Compression;
+use core::convert::TryInto;
+
+/// Returns the uncompressed size of the given compressed payload.
+pub fn uncompressed_size(compression: Compression, input: &[u8]) -> usize {
+    match compression {
+        Compression::Lz4 => {
+            let size = input
+                .get(..4)
+                .expect("lz4 payload is missing the size prefix");
+            u32::from_le_bytes(size.try_into().unwrap()) as usize
+        }
+    }
+}
+
+/// Decompresses the given payload into `output`.
+///
+/// The `output` slice must be exactly [`uncompressed_size`] bytes long. Panics if the payload
+/// is invalid.
+pub fn decompress_into(compression: Compression, input: &[u8], output: &mut [u8]) {
+    assert_eq!(output.len(), uncompressed_size(compression, input));
+    match compression {
+        Compression::Lz4 => {
+            if let Err(err) = lz4_decompress_into(&input[4..], output) {
+                panic!("failed to decompress lz4 payload: {}", err);
+            }
+        }
+    }
+}
+
+/// Decompresses a single block in the LZ4 block format.
+///
+/// See <https://github.com/lz4/lz4/blob/dev/doc/lz4_Block_format.md> for a description of the
+/// format.
+fn lz4_decompress_into(input: &[u8], output: &mut [u8]) -> Result<(), &'static str> {
+    const UNEXPECTED_END: &str = "unexpected end of input";
+
+    fn read_length(
+        input: &[u8],
+        in_pos: &mut usize,
+        mut length: usize,
+    ) -> Result<usize, &'static str> {
+        if length == 0xf {
+            loop {
+                let byte = *input.get(*in_pos).ok_or(UNEXPECTED_END)?;
+                *in_pos += 1;
+                length += usize::from(byte);
+                if byte != 0xff {
+                    break;
+                }
+            }
+        }
+        Ok(length)
+    }
+
+    let mut in_pos = 0;
+    let mut out_pos = 0;
+    loop {
+        let token = *input.get(in_pos).ok_or(UNEXPECTED_END)?;
+        in_pos += 1;
+
+        // copy literals
+        let literal_len = read_length(input, &mut in_pos, usize::from(token >> 4))?;
+        let literals = input
+            .get(in_pos..in_pos + literal_len)
+            .ok_or(UNEXPECTED_END)?;
+        output
+            .get_mut(out_pos..out_pos + literal_len)
+            .ok_or("output buffer too small")?
+            .copy_from_slice(literals);
+        in_pos += literal_len;
+        out_pos += literal_len;
+
+        // the last sequence consists of literals only
+        if in_pos == input.len() {
+            break;
+        }
+
+        // copy match, which might overlap with the bytes that are written
+        let offset = input.get(in_pos..in_pos + 2).ok_or(UNEXPECTED_END)?;
+        let offset = usize::from(u16::from_le_bytes([offset[0], offset[1]]));
+        in_pos += 2;
+        if offset == 0 || offset > out_pos {
+            return Err("invalid match offset");
+        }
+        let match_len = read_length(input, &mut in_pos, usize::from(token & 0xf))? + 4;
+        if out_pos + match_len > output.len() {
+            return Err("output buffer too small");
+        }
+        for i in out_pos..out_pos + match_len {
+            output[i] = output[i - offset];
+        }
+        out_pos += match_len;
+    }
+
+    if out_pos != output.len() {
+        return Err("uncompressed size does not match");
+    }
+    Ok(())
+}
@@ -0,0 +1,76 @@
+use core::arch::x86_64::{__cpuid, __cpuid_count, _rdseed64_step, _rdtsc};
+use x86_64::instructions::random::RdRand;
+
+/// A simple pseudo-random number generator for randomizing the kernel address space layout.
+///
+/// Uses the SplitMix64 algorithm, seeded from the hardware entropy sources of the CPU. This is
+/// not a cryptographically secure generator, but it is more than enough for picking a few
+/// virtual addresses since its seed is not observable by the kernel.
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    /// Creates a new generator that is seeded from `RDSEED`, `RDRAND`, and the time stamp counter.
+    ///
+    /// The hardware random number instructions are only used if the CPU supports them. The time
+    /// stamp counter is always mixed in as a fallback.
+    pub fn new() -> Self {
+        let mut rng = Rng { state: 0 };
+        if let Some(seed) = rdseed() {
+            rng.mix(seed);
+        }
+        if let Some(value) = RdRand::new().and_then(|rdrand| rdrand.get_u64()) {
+            rng.mix(value);
+        }
+        rng.mix(unsafe { _rdtsc() });
+        rng
+    }
+
+    /// Returns the next pseudo-random number.
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a pseudo-random number in the range `0..bound`.
+    ///
+    /// Panics if `bound` is zero.
+    pub fn gen_below(&mut self, bound: u64) -> u64 {
+        assert!(bound > 0, "bound must not be zero");
+        // rejection sampling to avoid a bias towards small numbers
+        let zone = u64::MAX - u64::MAX % bound;
+        loop {
+            let value = self.next_u64();
+            if value < zone {
+                return value % bound;
+            }
+        }
+    }
+
+    fn mix(&mut self, value: u64) {
+        self.state ^= value;
+        self.state = self.next_u64();
+    }
+}
+
+/// Reads a seed value using the `RDSEED` instruction, if supported by the CPU.
+#[allow(unused_unsafe)] // `__cpuid` is only safe to call on newer Rust versions
+fn rdseed() -> Option<u64> {
+    // CPUID.(EAX=07H, ECX=0H):EBX.RDSEED[bit 18]
+    let max_leaf = unsafe { __cpuid(0) }.eax;
+    if max_leaf < 7 || unsafe { __cpuid_count(7, 0) }.ebx & (1 << 18) == 0 {
+        return None;
+    }
+    // RDSEED might fail temporarily if the entropy source is exhausted, so retry a few times
+    for _ in 0..10 {
+        let mut seed = 0;
+        if unsafe { _rdseed64_step(&mut seed) } == 1 {
+            return Some(seed);
+        }
+    }
+    None
+}
@@ -1,7 +1,7 @@
 use crate::boot_info::{MemoryRegion, MemoryRegionKind};
 use core::mem::MaybeUninit;
 use x86_64::{
-    structures::paging::{FrameAllocator, PhysFrame, Size4KiB},
+    structures::paging::{FrameAllocator, PageSize, PhysFrame, Size2MiB, Size4KiB},
     PhysAddr,
 };
 
@@ -21,6 +21,8 @@ pub struct LegacyFrameAllocator<I, D> {
     memory_map: I,
     current_descriptor: Option<D>,
     next_frame: PhysFrame,
+    module_region: Option<(PhysAddr, PhysAddr)>,
+    kernel_file_region: Option<(PhysAddr, PhysAddr)>,
 }
 
 impl<I, D> LegacyFrameAllocator<I, D>
@@ -47,10 +49,54 @@ where
             memory_map,
             current_descriptor: None,
             next_frame: frame,
+            module_region: None,
+            kernel_file_region: None,
         }
     }
 
-    fn allocate_frame_from_descriptor(&mut self, descriptor: D) -> Option<PhysFrame> {
+    /// Marks the physical memory between `start` and `end` (exclusive) as containing modules.
+    ///
+    /// The region must not contain any frames that this allocator returns afterwards. It is
+    /// reported as [`MemoryRegionKind::Module`] in the memory map created by
+    /// [`construct_memory_map`].
+    pub fn set_module_region(&mut self, start: PhysAddr, end: PhysAddr) {
+        self.module_region = Some((start, end));
+    }
+
+    /// Marks the physical memory between `start` and `end` (exclusive) as containing the kernel
+    /// ELF file, which is no longer needed after its segments were copied to new frames.
+    ///
+    /// The region must lie before the first frame returned by this allocator and must not
+    /// overlap with the module region. It is reported as [`MemoryRegionKind::Usable`] in the
+    /// memory map created by [`construct_memory_map`], so the kernel can reuse it.
+    pub fn set_kernel_file_region(&mut self, start: PhysAddr, end: PhysAddr) {
+        self.kernel_file_region = Some((start, end));
+    }
+
+    /// Returns the start address of the next frame that this allocator might return.
+    ///
+    /// All usable memory below this address was either skipped or is already allocated.
+    pub fn next_free_addr(&self) -> PhysAddr {
+        self.next_frame.start_address()
+    }
+
+    /// Skips all frames below the given address, so that they are never returned by this
+    /// allocator.
+    ///
+    /// The skipped memory is reported as used by the bootloader in the memory map.
+    pub fn skip_to(&mut self, addr: PhysAddr) {
+        let frame = PhysFrame::containing_address(addr.align_up(Size4KiB::SIZE));
+        self.next_frame = self.next_frame.max(frame);
+    }
+
+    /// Allocates `count` contiguous frames from the given descriptor, starting at a physical
+    /// address that is aligned to `align`.
+    fn allocate_frames_from_descriptor(
+        &mut self,
+        descriptor: D,
+        count: u64,
+        align: u64,
+    ) -> Option<PhysFrame> {
         let start_addr = descriptor.start();
         let start_frame = PhysFrame::containing_address(start_addr);
         let end_addr = start_addr + descriptor.len();
@@ -60,16 +106,46 @@ where
         if self.next_frame < start_frame {
             self.next_frame = start_frame;
         }
+        let next_frame =
+            PhysFrame::containing_address(self.next_frame.start_address().align_up(align));
 
-        if self.next_frame < end_frame {
-            let ret = self.next_frame;
-            self.next_frame += 1;
-            Some(ret)
+        if next_frame + (count - 1) < end_frame {
+            self.next_frame = next_frame + count;
+            Some(next_frame)
         } else {
             None
         }
     }
 
+    /// Allocates `count` contiguous frames, starting at a physical address that is aligned to
+    /// `align`.
+    ///
+    /// Since this is a simple bump allocator, any frames that are skipped to satisfy the
+    /// alignment are not used anymore and are reported as used by the bootloader.
+    pub fn allocate_frames(&mut self, count: u64, align: u64) -> Option<PhysFrame> {
+        if let Some(current_descriptor) = self.current_descriptor {
+            match self.allocate_frames_from_descriptor(current_descriptor, count, align) {
+                Some(frame) => return Some(frame),
+                None => {
+                    self.current_descriptor = None;
+                }
+            }
+        }
+
+        // find next suitable descriptor
+        while let Some(descriptor) = self.memory_map.next() {
+            if descriptor.kind() != MemoryRegionKind::Usable {
+                continue;
+            }
+            if let Some(frame) = self.allocate_frames_from_descriptor(descriptor, count, align) {
+                self.current_descriptor = Some(descriptor);
+                return Some(frame);
+            }
+        }
+
+        None
+    }
+
     /// Returns the number of memory regions in the underlying memory map.
     ///
     /// The function always returns the same value, i.e. the length doesn't
@@ -92,7 +168,7 @@ where
     /// Converts this type to a boot info memory map.
     ///
     /// The memory map is placed in the given `regions` slice. The length of the given slice
-    /// must be at least the value returned by [`len`] pluse 1.
+    /// must be at least the value returned by [`memory_map_len`].
     ///
     /// The return slice is a subslice of `regions`, shortened to the actual number of regions.
     pub fn construct_memory_map(
@@ -101,6 +177,14 @@ where
     ) -> &mut [MemoryRegion] {
         let mut next_index = 0;
 
+        let mut special_regions = [
+            self.module_region
+                .map(|(start, end)| (start, end, MemoryRegionKind::Module)),
+            self.kernel_file_region
+                .map(|(start, end)| (start, end, MemoryRegionKind::Usable)),
+        ];
+        special_regions.sort_unstable_by_key(|region| region.map(|(start, _, _)| start));
+
         for descriptor in self.original {
             let mut start = descriptor.start();
             let end = start + descriptor.len();
@@ -108,18 +192,25 @@ where
             let kind = match descriptor.kind() {
                 MemoryRegionKind::Usable => {
                     if end <= next_free {
-                        MemoryRegionKind::Bootloader
+                        Self::add_used_region(
+                            start,
+                            end,
+                            &special_regions,
+                            regions,
+                            &mut next_index,
+                        );
+                        continue;
                     } else if descriptor.start() >= next_free {
                         MemoryRegionKind::Usable
                     } else {
                         // part of the region is used -> add it separately
-                        let used_region = MemoryRegion {
-                            start: descriptor.start().as_u64(),
-                            end: next_free.as_u64(),
-                            kind: MemoryRegionKind::Bootloader,
-                        };
-                        Self::add_region(used_region, regions, &mut next_index)
-                            .expect("Failed to add memory region");
+                        Self::add_used_region(
+                            start,
+                            next_free,
+                            &special_regions,
+                            regions,
+                            &mut next_index,
+                        );
 
                         // add unused part normally
                         start = next_free;
@@ -155,6 +246,50 @@ where
         unsafe { MaybeUninit::slice_assume_init_mut(initialized) }
     }
 
+    /// Returns the number of regions that [`construct_memory_map`] might create.
+    ///
+    /// The region containing the next free frame might be split into a used and an unused
+    /// part. Through `add_used_region`, the module and kernel file regions might split a used
+    /// region into used/module/used and used/usable/used parts.
+    pub fn memory_map_len(&self) -> usize {
+        self.len() + 5
+    }
+
+    /// Adds a region that was used by the bootloader, splitting out the parts that overlap
+    /// with the given special regions (e.g. the module region).
+    ///
+    /// The special regions must be sorted by their start address and must not overlap.
+    fn add_used_region(
+        start: PhysAddr,
+        end: PhysAddr,
+        special_regions: &[Option<(PhysAddr, PhysAddr, MemoryRegionKind)>],
+        regions: &mut [MaybeUninit<MemoryRegion>],
+        next_index: &mut usize,
+    ) {
+        let mut add_part = |start: PhysAddr, end: PhysAddr, kind| {
+            if start < end {
+                let region = MemoryRegion {
+                    start: start.as_u64(),
+                    end: end.as_u64(),
+                    kind,
+                };
+                Self::add_region(region, regions, next_index).expect("Failed to add memory region");
+            }
+        };
+
+        let mut current = start;
+        for &(special_start, special_end, kind) in special_regions.iter().flatten() {
+            let special_start = special_start.max(current);
+            let special_end = special_end.min(end);
+            if special_start < special_end {
+                add_part(current, special_start, MemoryRegionKind::Bootloader);
+                add_part(special_start, special_end, kind);
+                current = special_end;
+            }
+        }
+        add_part(current, end, MemoryRegionKind::Bootloader);
+    }
+
     fn add_region(
         region: MemoryRegion,
         regions: &mut [MaybeUninit<MemoryRegion>],
@@ -178,26 +313,27 @@ where
     I::Item: LegacyMemoryRegion,
 {
     fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-        if let Some(current_descriptor) = self.current_descriptor {
-            match self.allocate_frame_from_descriptor(current_descriptor) {
-                Some(frame) => return Some(frame),
-                None => {
-                    self.current_descriptor = None;
-                }
-            }
-        }
+        self.allocate_frames(1, Size4KiB::SIZE)
+    }
+}
 
-        // find next suitable descriptor
-        while let Some(descriptor) = self.memory_map.next() {
-            if descriptor.kind() != MemoryRegionKind::Usable {
-                continue;
-            }
-            if let Some(frame) = self.allocate_frame_from_descriptor(descriptor) {
-                self.current_descriptor = Some(descriptor);
-                return Some(frame);
-            }
-        }
+unsafe impl<I, D> FrameAllocator<Size2MiB> for LegacyFrameAllocator<I, D>
+where
+    I: ExactSizeIterator<Item = D> + Clone,
+    I::Item: LegacyMemoryRegion,
+{
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        // don't skip any memory regions for 4KiB allocations if no 2MiB frame is available
+        let memory_map = self.memory_map.clone();
+        let current_descriptor = self.current_descriptor;
+        let next_frame = self.next_frame;
 
-        None
+        let frame = self.allocate_frames(Size2MiB::SIZE / Size4KiB::SIZE, Size2MiB::SIZE);
+        if frame.is_none() {
+            self.memory_map = memory_map;
+            self.current_descriptor = current_descriptor;
+            self.next_frame = next_frame;
+        }
+        frame.map(|frame| PhysFrame::from_start_address(frame.start_address()).unwrap())
     }
 }
@@ -1,5 +1,7 @@
+use crate::binary::{entropy::Rng, CONFIG};
 use core::convert::TryInto;
 use x86_64::{
+    align_up,
     structures::paging::{Page, PageTableIndex},
     VirtAddr,
 };
@@ -10,43 +12,70 @@ use xmas_elf::program::ProgramHeader;
 /// Useful for determining a free virtual memory block, e.g. for mapping additional data.
 pub struct UsedLevel4Entries {
     entry_state: [bool; 512], // whether an entry is in use by the kernel
+    /// Used to randomize the returned entries if the `aslr` config option is enabled.
+    rng: Option<Rng>,
 }
 
 impl UsedLevel4Entries {
     /// Initializes a new instance from the given ELF program segments.
     ///
-    /// Marks the virtual address range of all segments as used.
-    pub fn new<'a>(segments: impl Iterator<Item = ProgramHeader<'a>>) -> Self {
+    /// Marks the virtual address range of all segments as used. The `virtual_address_offset`
+    /// is added to the segment addresses, which is required for position-independent kernels.
+    ///
+    /// If the `aslr` config option is enabled, the returned free entries are randomized.
+    pub fn new<'a>(
+        segments: impl Iterator<Item = ProgramHeader<'a>>,
+        virtual_address_offset: u64,
+    ) -> Self {
         let mut used = UsedLevel4Entries {
             entry_state: [false; 512],
+            rng: CONFIG.aslr.then(Rng::new),
         };
 
         used.entry_state[0] = true; // TODO: Can we do this dynamically?
 
         for segment in segments {
-            let start_page: Page = Page::containing_address(VirtAddr::new(segment.virtual_addr()));
-            let end_page: Page = Page::containing_address(VirtAddr::new(
-                segment.virtual_addr() + segment.mem_size(),
-            ));
-
-            for p4_index in u64::from(start_page.p4_index())..=u64::from(end_page.p4_index()) {
-                used.entry_state[p4_index as usize] = true;
-            }
+            let virtual_addr = segment.virtual_addr() + virtual_address_offset;
+            used.mark_range_as_used(
+                VirtAddr::new(virtual_addr),
+                VirtAddr::new(virtual_addr + segment.mem_size()),
+            );
         }
 
         used
     }
 
+    /// Marks all level 4 entries that overlap the given virtual address range as used.
+    ///
+    /// Both `start` and `end` are inclusive.
+    pub fn mark_range_as_used(&mut self, start: VirtAddr, end: VirtAddr) {
+        let start_page: Page = Page::containing_address(start);
+        let end_page: Page = Page::containing_address(end);
+
+        for p4_index in u64::from(start_page.p4_index())..=u64::from(end_page.p4_index()) {
+            self.entry_state[p4_index as usize] = true;
+        }
+    }
+
     /// Returns a unused level 4 entry and marks it as used.
     ///
     /// Since this method marks each returned index as used, it can be used multiple times
     /// to determine multiple unused virtual memory regions.
+    ///
+    /// Returns the first unused entry, or a random unused entry if ASLR is enabled.
     pub fn get_free_entry(&mut self) -> PageTableIndex {
+        let free_entries = self.entry_state.iter().filter(|&&used| !used).count();
+        let skip = match &mut self.rng {
+            Some(rng) if free_entries > 0 => rng.gen_below(free_entries as u64) as usize,
+            _ => 0,
+        };
+
         let (idx, entry) = self
             .entry_state
             .iter_mut()
             .enumerate()
-            .find(|(_, &mut entry)| entry == false)
+            .filter(|(_, &mut entry)| entry == false)
+            .nth(skip)
             .expect("no usable level 4 entries found");
 
         *entry = true;
@@ -61,4 +90,20 @@ impl UsedLevel4Entries {
         Page::from_page_table_indices_1gib(self.get_free_entry(), PageTableIndex::new(0))
             .start_address()
     }
+
+    /// Returns a random offset for placing a memory region of the given `size` within a level 4
+    /// entry.
+    ///
+    /// The offset is a multiple of `align` and the region stays within the bounds of the level 4
+    /// entry. Always returns `0` if ASLR is disabled.
+    pub fn random_offset(&mut self, size: u64, align: u64) -> u64 {
+        const LEVEL_4_ENTRY_SIZE: u64 = 512 * 1024 * 1024 * 1024;
+
+        let rng = match &mut self.rng {
+            Some(rng) => rng,
+            None => return 0,
+        };
+        let slots = LEVEL_4_ENTRY_SIZE.saturating_sub(align_up(size, align)) / align + 1;
+        rng.gen_below(slots) * align
+    }
 }
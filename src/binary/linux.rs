@@ -0,0 +1,457 @@
+use crate::{
+    binary::{
+        kernel_load_failed,
+        legacy_memory_region::{LegacyFrameAllocator, LegacyMemoryRegion},
+        multiboot2::memory_type,
+        parsed_config::CONFIG,
+        PageTables, SystemInfo, PAGE_SIZE,
+    },
+    boot_info::{MemoryRegion, Module, PixelFormat},
+    linux::{BzImage, SETUP_HEADER_OFFSET},
+};
+use core::{arch::global_asm, mem::MaybeUninit, ptr, slice};
+use x86_64::{
+    align_down, align_up,
+    structures::paging::{Mapper, OffsetPageTable, PageTableFlags, PhysFrame, Size4KiB},
+    PhysAddr,
+};
+
+/// The size of the `boot_params` structure, which is also known as the zero page.
+const BOOT_PARAMS_SIZE: usize = 4096;
+/// Kernels without the `XLF_CAN_BE_LOADED_ABOVE_4G` flag must be located below 4GiB.
+const MAX_LOW_ADDR: u64 = 0x1_0000_0000;
+/// The number of memory map entries that fit into the `boot_params` structure.
+const E820_MAX_ENTRIES: usize = 128;
+/// The size of a memory map entry in the `boot_params` structure.
+const E820_ENTRY_SIZE: usize = 20;
+/// The `type_of_loader` value of bootloaders that have no assigned ID.
+const LOADER_TYPE_UNDEFINED: u8 = 0xff;
+/// The `orig_video_isVGA` value of VESA linear framebuffers.
+const VIDEO_TYPE_VLFB: u8 = 0x23;
+/// Signals that the `ext_lfb_base` field contains the upper 32 bits of the framebuffer address.
+const VIDEO_CAPABILITY_64BIT_BASE: u32 = 1 << 1;
+/// The size of the stack that the kernel is entered with.
+const STACK_SIZE: u64 = 4 * PAGE_SIZE;
+
+/// Offsets of the fields of the `boot_params` structure that are set by the bootloader.
+mod field {
+    pub const ORIG_VIDEO_IS_VGA: usize = 0x0f;
+    pub const LFB_WIDTH: usize = 0x12;
+    pub const LFB_HEIGHT: usize = 0x14;
+    pub const LFB_DEPTH: usize = 0x16;
+    pub const LFB_BASE: usize = 0x18;
+    pub const LFB_SIZE: usize = 0x1c;
+    pub const LFB_LINELENGTH: usize = 0x24;
+    pub const RED_SIZE: usize = 0x26;
+    pub const RSVD_SIZE: usize = 0x2c;
+    pub const CAPABILITIES: usize = 0x36;
+    pub const EXT_LFB_BASE: usize = 0x3a;
+    pub const ACPI_RSDP_ADDR: usize = 0x70;
+    pub const EXT_RAMDISK_IMAGE: usize = 0xc0;
+    pub const EXT_RAMDISK_SIZE: usize = 0xc4;
+    pub const EXT_CMD_LINE_PTR: usize = 0xc8;
+    pub const E820_ENTRIES: usize = 0x1e8;
+    pub const TYPE_OF_LOADER: usize = 0x210;
+    pub const RAMDISK_IMAGE: usize = 0x218;
+    pub const RAMDISK_SIZE: usize = 0x21c;
+    pub const CMD_LINE_PTR: usize = 0x228;
+    pub const E820_TABLE: usize = 0x2d0;
+}
+
+/// Checks whether the given kernel file is a Linux `bzImage` and parses its setup header.
+///
+/// Returns `None` if the kernel is no Linux kernel image. Panics if the kernel is a Linux kernel
+/// image, but can't be booted.
+pub fn detect(kernel_bytes: &[u8]) -> Option<BzImage<'_>> {
+    BzImage::parse(kernel_bytes).map(|result| result.unwrap_or_else(|err| kernel_load_failed(err)))
+}
+
+/// Loads the given Linux kernel and switches to its 64-bit entry point.
+///
+/// The protected-mode kernel is copied to newly allocated memory, since Linux kernels are
+/// relocatable. The `boot_params` structure is created from the given arguments, with the
+/// modules concatenated to a single initrd. The kernel is entered with the kernel page table of
+/// the given `page_tables`, which identity-maps the kernel, its boot parameters, and its stack,
+/// as required by the x86 boot protocol.
+pub fn load_and_switch_to_kernel<I, D>(
+    kernel: &BzImage,
+    mut frame_allocator: LegacyFrameAllocator<I, D>,
+    mut page_tables: PageTables,
+    system_info: SystemInfo,
+    modules: &[Module],
+) -> !
+where
+    I: ExactSizeIterator<Item = D> + Clone,
+    D: LegacyMemoryRegion,
+{
+    kernel
+        .check_cmdline(CONFIG.kernel_cmdline)
+        .unwrap_or_else(|err| kernel_load_failed(err));
+    let max_addr = match kernel.can_be_loaded_above_4g() {
+        true => u64::MAX,
+        false => MAX_LOW_ADDR,
+    };
+
+    log::info!("Loading Linux kernel");
+    // the kernel decompresses itself in place, so it needs `init_size` bytes at its load address
+    let kernel_addr = allocate(
+        &mut frame_allocator,
+        kernel.init_size(),
+        kernel.kernel_alignment().max(PAGE_SIZE),
+        max_addr,
+        "kernel",
+    );
+    let protected_mode_kernel = kernel.protected_mode_kernel();
+    unsafe {
+        ptr::copy_nonoverlapping(
+            protected_mode_kernel.as_ptr(),
+            kernel_addr as *mut u8,
+            protected_mode_kernel.len(),
+        )
+    };
+
+    // the command line is stored directly behind the boot parameters
+    let params_len = BOOT_PARAMS_SIZE + CONFIG.kernel_cmdline.len() + 1;
+    let params_addr = allocate(
+        &mut frame_allocator,
+        params_len as u64,
+        PAGE_SIZE,
+        max_addr,
+        "boot parameters",
+    );
+    let params = unsafe { slice::from_raw_parts_mut(params_addr as *mut u8, params_len) };
+    params.fill(0);
+    let (params, cmdline) = params.split_at_mut(BOOT_PARAMS_SIZE);
+    cmdline[..CONFIG.kernel_cmdline.len()].copy_from_slice(CONFIG.kernel_cmdline.as_bytes());
+    let mut params = BootParams(params);
+
+    let setup_header = kernel.setup_header();
+    params.write_bytes(SETUP_HEADER_OFFSET, setup_header);
+    params.write_u8(field::TYPE_OF_LOADER, LOADER_TYPE_UNDEFINED);
+    let cmdline_addr = params_addr + BOOT_PARAMS_SIZE as u64;
+    params.write_u32(field::CMD_LINE_PTR, cmdline_addr as u32);
+    params.write_u32(field::EXT_CMD_LINE_PTR, (cmdline_addr >> 32) as u32);
+
+    if let Some((initrd_addr, initrd_len)) = create_initrd(
+        &mut frame_allocator,
+        modules,
+        kernel.initrd_addr_max().saturating_add(1),
+    ) {
+        params.write_u32(field::RAMDISK_IMAGE, initrd_addr as u32);
+        params.write_u32(field::EXT_RAMDISK_IMAGE, (initrd_addr >> 32) as u32);
+        params.write_u32(field::RAMDISK_SIZE, initrd_len as u32);
+        params.write_u32(field::EXT_RAMDISK_SIZE, (initrd_len >> 32) as u32);
+    }
+    write_screen_info(&mut params, &system_info);
+    if let Some(rsdp_addr) = system_info.rsdp_addr {
+        params.write_u64(field::ACPI_RSDP_ADDR, rsdp_addr.as_u64());
+    }
+
+    let stack_addr = allocate(
+        &mut frame_allocator,
+        STACK_SIZE,
+        PAGE_SIZE,
+        u64::MAX,
+        "stack",
+    );
+
+    let kernel_page_table = &mut page_tables.kernel;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    identity_map(
+        kernel_page_table,
+        &mut frame_allocator,
+        kernel_addr,
+        kernel.init_size(),
+        flags,
+    );
+    identity_map(
+        kernel_page_table,
+        &mut frame_allocator,
+        params_addr,
+        params_len as u64,
+        flags,
+    );
+    identity_map(
+        kernel_page_table,
+        &mut frame_allocator,
+        stack_addr,
+        STACK_SIZE,
+        flags,
+    );
+    // identity-map the trampoline and its GDT, so that we don't get an immediate pagefault
+    // after switching the active page table
+    let trampoline_addr = align_down(linux_enter as *const () as u64, PAGE_SIZE);
+    identity_map(
+        kernel_page_table,
+        &mut frame_allocator,
+        trampoline_addr,
+        2 * PAGE_SIZE,
+        PageTableFlags::PRESENT,
+    );
+
+    let memory_regions_len = frame_allocator.memory_map_len();
+    let regions_addr = allocate(
+        &mut frame_allocator,
+        (memory_regions_len * core::mem::size_of::<MemoryRegion>()) as u64,
+        PAGE_SIZE,
+        u64::MAX,
+        "memory map",
+    );
+    let regions = unsafe {
+        slice::from_raw_parts_mut(
+            regions_addr as *mut MaybeUninit<MemoryRegion>,
+            memory_regions_len,
+        )
+    };
+    let memory_regions = frame_allocator.construct_memory_map(regions);
+    write_memory_map(&mut params, memory_regions);
+
+    let entry_point = kernel_addr + kernel.entry_point_offset();
+    log::info!("Jumping to Linux kernel entry point at {:#x}", entry_point);
+    unsafe {
+        linux_enter(
+            entry_point,
+            params_addr,
+            page_tables.kernel_level_4_frame.start_address().as_u64(),
+            stack_addr + STACK_SIZE,
+        )
+    }
+}
+
+/// Concatenates the given modules to a single initrd and returns its address and length.
+///
+/// The kernel unpacks all archives of concatenated initramfs images. Each archive must start at
+/// a 4-byte aligned offset, so the modules are padded with zeros.
+fn create_initrd<I, D>(
+    frame_allocator: &mut LegacyFrameAllocator<I, D>,
+    modules: &[Module],
+    max_addr: u64,
+) -> Option<(u64, u64)>
+where
+    I: ExactSizeIterator<Item = D> + Clone,
+    D: LegacyMemoryRegion,
+{
+    if modules.is_empty() {
+        return None;
+    }
+    let padded_len = |module: &Module| align_up(module.len as u64, 4);
+    let len = modules.iter().map(padded_len).sum();
+    let addr = allocate(frame_allocator, len, PAGE_SIZE, max_addr, "initrd");
+    let mut offset = 0;
+    for module in modules {
+        // the physical memory is identity-mapped
+        let dest = (addr + offset) as *mut u8;
+        unsafe {
+            ptr::copy_nonoverlapping(module.phys_addr as *const u8, dest, module.len);
+            ptr::write_bytes(
+                dest.add(module.len),
+                0,
+                (padded_len(module) - module.len as u64) as usize,
+            );
+        }
+        offset += padded_len(module);
+    }
+    log::info!(
+        "Created initrd from {} modules at {:#x}",
+        modules.len(),
+        addr
+    );
+    Some((addr, len))
+}
+
+/// Describes the framebuffer as VESA linear framebuffer in the `screen_info` structure.
+fn write_screen_info(params: &mut BootParams, system_info: &SystemInfo) {
+    let framebuffer = system_info.framebuffer_info;
+    let framebuffer_addr = system_info.framebuffer_addr.as_u64();
+    params.write_u8(field::ORIG_VIDEO_IS_VGA, VIDEO_TYPE_VLFB);
+    params.write_u16(field::LFB_WIDTH, framebuffer.horizontal_resolution as u16);
+    params.write_u16(field::LFB_HEIGHT, framebuffer.vertical_resolution as u16);
+    params.write_u16(field::LFB_DEPTH, (framebuffer.bytes_per_pixel * 8) as u16);
+    params.write_u32(field::LFB_BASE, framebuffer_addr as u32);
+    if framebuffer_addr >> 32 != 0 {
+        params.write_u32(field::EXT_LFB_BASE, (framebuffer_addr >> 32) as u32);
+        params.write_u32(field::CAPABILITIES, VIDEO_CAPABILITY_64BIT_BASE);
+    }
+    // the size of VESA framebuffers is given in units of 64KiB
+    let size = align_up(framebuffer.byte_len as u64, 0x1_0000) >> 16;
+    params.write_u32(field::LFB_SIZE, size as u32);
+    params.write_u16(
+        field::LFB_LINELENGTH,
+        (framebuffer.stride * framebuffer.bytes_per_pixel) as u16,
+    );
+    // mask size and field position of red, green, and blue
+    let fields = match framebuffer.pixel_format {
+        PixelFormat::RGB => [8, 0, 8, 8, 8, 16],
+        PixelFormat::BGR => [8, 16, 8, 8, 8, 0],
+        // report grayscale as equal color channels
+        PixelFormat::U8 => [8, 0, 8, 0, 8, 0],
+    };
+    params.write_bytes(field::RED_SIZE, &fields);
+    if framebuffer.bytes_per_pixel == 4 {
+        params.write_bytes(field::RSVD_SIZE, &[8, 24]);
+    }
+}
+
+/// Writes the given memory regions to the E820 memory map of the `boot_params` structure.
+///
+/// The memory map only has room for a limited number of entries, so adjacent regions of the
+/// same type are merged.
+fn write_memory_map(params: &mut BootParams, regions: &mut [MemoryRegion]) {
+    regions.sort_unstable_by_key(|region| region.start);
+    let mut entries = 0;
+    let mut current: Option<(u64, u64, u32)> = None;
+    for region in regions.iter().filter(|region| region.end > region.start) {
+        // the Multiboot2 memory types are identical to the E820 types
+        let region_type = memory_type(region.kind);
+        if let Some((_, end, current_type)) = &mut current {
+            if *end == region.start && *current_type == region_type {
+                *end = region.end;
+                continue;
+            }
+        }
+        if let Some(entry) = current.replace((region.start, region.end, region_type)) {
+            params.write_e820_entry(entries, entry);
+            entries += 1;
+        }
+    }
+    if let Some(entry) = current {
+        params.write_e820_entry(entries, entry);
+        entries += 1;
+    }
+    params.write_u8(field::E820_ENTRIES, entries as u8);
+}
+
+/// Allocates physically contiguous frames for `len` bytes and returns their start address.
+///
+/// Panics if the allocated memory does not end below `max_addr`.
+fn allocate<I, D>(
+    frame_allocator: &mut LegacyFrameAllocator<I, D>,
+    len: u64,
+    align: u64,
+    max_addr: u64,
+    name: &str,
+) -> u64
+where
+    I: ExactSizeIterator<Item = D> + Clone,
+    D: LegacyMemoryRegion,
+{
+    let frames = align_up(len, PAGE_SIZE) / PAGE_SIZE;
+    let start = frame_allocator
+        .allocate_frames(frames, align)
+        .unwrap_or_else(|| panic!("frame allocation for Linux {} failed", name))
+        .start_address()
+        .as_u64();
+    if start + len > max_addr {
+        panic!(
+            "Linux {} must be located below {:#x}, but it was allocated at {:#x}",
+            name, max_addr, start
+        );
+    }
+    start
+}
+
+/// Identity-maps the frames of the given physical memory range in the given page table.
+fn identity_map<I, D>(
+    page_table: &mut OffsetPageTable,
+    frame_allocator: &mut LegacyFrameAllocator<I, D>,
+    start: u64,
+    len: u64,
+    flags: PageTableFlags,
+) where
+    I: ExactSizeIterator<Item = D> + Clone,
+    D: LegacyMemoryRegion,
+{
+    let start_frame: PhysFrame = PhysFrame::containing_address(PhysAddr::new(start));
+    let end_frame = PhysFrame::containing_address(PhysAddr::new(start + len - 1));
+    for frame in PhysFrame::<Size4KiB>::range_inclusive(start_frame, end_frame) {
+        match unsafe { page_table.identity_map(frame, flags, frame_allocator) } {
+            Ok(tlb) => tlb.flush(),
+            Err(err) => panic!("failed to identity map frame {:?}: {:?}", frame, err),
+        }
+    }
+}
+
+/// Writes the fields of the zeroed `boot_params` structure.
+struct BootParams(&'static mut [u8]);
+
+impl BootParams {
+    fn write_bytes(&mut self, offset: usize, bytes: &[u8]) {
+        self.0[offset..][..bytes.len()].copy_from_slice(bytes);
+    }
+
+    fn write_u8(&mut self, offset: usize, value: u8) {
+        self.write_bytes(offset, &[value]);
+    }
+
+    fn write_u16(&mut self, offset: usize, value: u16) {
+        self.write_bytes(offset, &value.to_le_bytes());
+    }
+
+    fn write_u32(&mut self, offset: usize, value: u32) {
+        self.write_bytes(offset, &value.to_le_bytes());
+    }
+
+    fn write_u64(&mut self, offset: usize, value: u64) {
+        self.write_bytes(offset, &value.to_le_bytes());
+    }
+
+    /// Writes the E820 memory map entry with the given index.
+    fn write_e820_entry(&mut self, index: usize, (start, end, entry_type): (u64, u64, u32)) {
+        if index >= E820_MAX_ENTRIES {
+            panic!(
+                "the memory map has more than {} entries, which is not supported for Linux kernels",
+                E820_MAX_ENTRIES
+            );
+        }
+        let offset = field::E820_TABLE + index * E820_ENTRY_SIZE;
+        self.write_u64(offset, start);
+        self.write_u64(offset + 8, end - start);
+        self.write_u32(offset + 16, entry_type);
+    }
+}
+
+extern "sysv64" {
+    /// Switches to the given page table and stack and jumps to the 64-bit entry point of a
+    /// Linux kernel.
+    ///
+    /// Loads a GDT with the `__BOOT_CS` (0x10) and `__BOOT_DS` (0x18) segments that the x86 boot
+    /// protocol requires and passes the address of the boot parameters in `RSI`. Must be
+    /// identity-mapped in both the current and the given page table.
+    fn linux_enter(entry_point: u64, boot_params: u64, page_table: u64, stack_top: u64) -> !;
+}
+
+global_asm!(
+    ".global linux_enter",
+    "linux_enter:",
+    "cli",
+    // load the GDT through a descriptor on the current stack
+    "lea rax, [rip + linux_gdt]",
+    "sub rsp, 16",
+    "mov [rsp + 2], rax",
+    "mov word ptr [rsp], 4 * 8 - 1",
+    "lgdt [rsp]",
+    // the current stack is not mapped in the kernel page table
+    "mov cr3, rdx",
+    "mov rsp, rcx",
+    // far return to reload the code segment register
+    "push 0x10",
+    "lea rax, [rip + linux_enter_boot_cs]",
+    "push rax",
+    "retfq",
+    "linux_enter_boot_cs:",
+    "mov ax, 0x18",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov fs, ax",
+    "mov gs, ax",
+    "mov ss, ax",
+    "jmp rdi",
+    // the accessed bits are already set because the GDT is mapped read-only, so the CPU can't
+    // set them when loading the segments
+    ".align 8",
+    "linux_gdt:",
+    ".quad 0",
+    ".quad 0",
+    ".quad 0x00af9b000000ffff", // 64-bit code segment
+    ".quad 0x00cf93000000ffff", // data segment
+);
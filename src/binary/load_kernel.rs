@@ -1,21 +1,107 @@
 use crate::{
-    binary::{level_4_entries::UsedLevel4Entries, PAGE_SIZE},
-    boot_info::TlsTemplate,
+    binary::{level_4_entries::UsedLevel4Entries, CONFIG, PAGE_SIZE},
+    boot_info::{KernelSegment, TlsTemplate},
+    kernel_check::check_kernel,
 };
+use core::{convert::TryInto, mem, ops::Range, ptr, slice};
 use x86_64::{
     align_up,
     structures::paging::{
-        mapper::MapperAllSizes, FrameAllocator, Page, PageSize, PageTableFlags as Flags, PhysFrame,
-        Size4KiB,
+        mapper::{MapToError, MappedFrame, MapperAllSizes, TranslateResult},
+        FrameAllocator, Page, PageSize, PageTableFlags as Flags, PhysFrame, Size2MiB, Size4KiB,
+        Translate,
     },
     PhysAddr, VirtAddr,
 };
 use xmas_elf::{
-    header,
-    program::{self, ProgramHeader, Type},
+    dynamic, header,
+    program::{self, ProgramHeader, SegmentData, Type},
+    sections::{Rela, SectionHeader, ShType},
+    symbol_table::{Binding, DynEntry64, Entry},
     ElfFile,
 };
 
+pub use crate::kernel_check::LoadKernelError;
+
+// Relocation types of the x86_64 System V ABI that we support.
+const R_X86_64_NONE: u32 = 0;
+const R_X86_64_64: u32 = 1;
+const R_X86_64_GLOB_DAT: u32 = 6;
+const R_X86_64_RELATIVE: u32 = 8;
+
+// Special section indices of symbol table entries.
+const SHN_UNDEF: u16 = 0;
+const SHN_ABS: u16 = 0xfff1;
+
+/// The maximum number of loadable segments that a kernel can have.
+pub const MAX_KERNEL_SEGMENTS: usize = 32;
+
+/// The loadable segments of the kernel, collected while loading it.
+#[derive(Debug, Clone, Copy)]
+pub struct KernelSegmentList {
+    segments: [KernelSegment; MAX_KERNEL_SEGMENTS],
+    len: usize,
+}
+
+impl KernelSegmentList {
+    fn new() -> Self {
+        const EMPTY: KernelSegment = KernelSegment {
+            virt_addr: 0,
+            mem_size: 0,
+            phys_addr: 0,
+            file_size: 0,
+            flags: 0,
+        };
+        Self {
+            segments: [EMPTY; MAX_KERNEL_SEGMENTS],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, segment: KernelSegment) -> Result<(), &'static str> {
+        let slot = self
+            .segments
+            .get_mut(self.len)
+            .ok_or("Kernel has too many loadable segments")?;
+        *slot = segment;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Returns the collected segments as a slice.
+    pub fn as_slice(&self) -> &[KernelSegment] {
+        &self.segments[..self.len]
+    }
+}
+
+/// Describes a kernel that was loaded by [`load_kernel`].
+pub struct LoadedKernel {
+    /// The virtual address of the kernel entry point.
+    pub entry_point: VirtAddr,
+    /// The thread local storage template of the kernel, if any.
+    pub tls_template: Option<TlsTemplate>,
+    /// The level 4 page table entries that are used by the kernel.
+    pub used_entries: UsedLevel4Entries,
+    /// The offset that was added to all virtual addresses of the kernel.
+    ///
+    /// Non-zero only for position-independent kernels.
+    pub kernel_base: u64,
+    /// The loadable segments of the kernel.
+    pub segments: KernelSegmentList,
+    /// The location of the symbol table of the kernel, if the `keep-symbols` config option is
+    /// enabled and the kernel has a symbol table.
+    pub symbols: Option<SymbolTables>,
+}
+
+/// The location of the symbol table and its associated string table in the kernel ELF file.
+#[derive(Debug, Clone)]
+pub struct SymbolTables {
+    /// Byte range of the `.symtab` section within the ELF file.
+    pub symtab: Range<usize>,
+    /// Byte range of the `.strtab` section within the ELF file.
+    pub strtab: Range<usize>,
+}
+
 struct Loader<'a, M, F> {
     elf_file: ElfFile<'a>,
     inner: Inner<'a, M, F>,
@@ -23,32 +109,70 @@ struct Loader<'a, M, F> {
 
 struct Inner<'a, M, F> {
     kernel_offset: PhysAddr,
+    kernel_len: u64,
+    /// Offset added to all virtual addresses of the ELF file.
+    ///
+    /// Always zero for normal executables. For position-independent executables, this is the
+    /// virtual base address that the kernel is loaded at.
+    virtual_address_offset: u64,
     page_table: &'a mut M,
     frame_allocator: &'a mut F,
 }
 
 impl<'a, M, F> Loader<'a, M, F>
 where
-    M: MapperAllSizes,
-    F: FrameAllocator<Size4KiB>,
+    M: MapperAllSizes + Translate,
+    F: FrameAllocator<Size4KiB> + FrameAllocator<Size2MiB>,
 {
     fn new(
         bytes: &'a [u8],
         page_table: &'a mut M,
         frame_allocator: &'a mut F,
-    ) -> Result<Self, &'static str> {
+    ) -> Result<Self, LoadKernelError> {
         log::info!("Elf file loaded at {:#p}", bytes);
         let kernel_offset = PhysAddr::new(&bytes[0] as *const u8 as u64);
         if !kernel_offset.is_aligned(PAGE_SIZE) {
-            return Err("Loaded kernel ELF file is not sufficiently aligned");
+            return Err("Loaded kernel ELF file is not sufficiently aligned".into());
         }
 
-        let elf_file = ElfFile::new(bytes)?;
-        header::sanity_check(&elf_file)?;
+        let elf_file = ElfFile::new(bytes).map_err(LoadKernelError::InvalidElf)?;
+        check_kernel(&elf_file, CONFIG.strict_wx)?;
+
+        let virtual_address_offset = match elf_file.header.pt2.type_().as_type() {
+            header::Type::Executable => 0,
+            // a position-independent executable (PIE), which we load into an unused level 4
+            // entry (at a random offset if ASLR is enabled)
+            header::Type::SharedObject => {
+                let load_segments = || {
+                    elf_file
+                        .program_iter()
+                        .filter(|segment| matches!(segment.get_type(), Ok(Type::Load)))
+                };
+                let size = load_segments()
+                    .map(|segment| segment.virtual_addr() + segment.mem_size())
+                    .max()
+                    .unwrap_or(0);
+                let align = load_segments()
+                    .map(|segment| segment.align())
+                    .fold(PAGE_SIZE, u64::max);
+                if !align.is_power_of_two() {
+                    return Err("Kernel segment alignment is not a power of two".into());
+                }
+
+                let mut used_entries = UsedLevel4Entries::new(elf_file.program_iter(), 0);
+                let base = used_entries.get_free_address();
+                base.as_u64() + used_entries.random_offset(size, align)
+            }
+            _ => return Err(LoadKernelError::WrongType),
+        };
+        log::info!("Virtual address offset: {:#x}", virtual_address_offset);
+
         let loader = Loader {
             elf_file,
             inner: Inner {
                 kernel_offset,
+                kernel_len: bytes.len() as u64,
+                virtual_address_offset,
                 page_table,
                 frame_allocator,
             },
@@ -57,48 +181,122 @@ where
         Ok(loader)
     }
 
-    fn load_segments(&mut self) -> Result<Option<TlsTemplate>, &'static str> {
+    fn load_segments(
+        &mut self,
+    ) -> Result<(Option<TlsTemplate>, KernelSegmentList), LoadKernelError> {
         let mut tls_template = None;
+        let mut dynamic_segment = None;
+        let mut relro_segment = None;
+        let mut segments = KernelSegmentList::new();
         for program_header in self.elf_file.program_iter() {
             program::sanity_check(program_header, &self.elf_file)?;
             match program_header.get_type()? {
-                Type::Load => self.inner.handle_load_segment(program_header)?,
+                Type::Load => {
+                    self.inner.handle_load_segment(program_header)?;
+                    segments.push(KernelSegment {
+                        virt_addr: program_header.virtual_addr()
+                            + self.inner.virtual_address_offset,
+                        mem_size: program_header.mem_size(),
+                        phys_addr: 0, // set after all segments are loaded
+                        file_size: program_header.file_size(),
+                        flags: program_header.flags().0,
+                    })?;
+                }
                 Type::Tls => {
                     if tls_template.is_none() {
                         tls_template = Some(self.inner.handle_tls_segment(program_header)?);
                     } else {
-                        return Err("multiple TLS segments not supported");
+                        return Err("multiple TLS segments not supported".into());
+                    }
+                }
+                Type::Dynamic => dynamic_segment = Some(program_header),
+                Type::GnuRelro => {
+                    if relro_segment.is_none() {
+                        relro_segment = Some(program_header);
+                    } else {
+                        return Err("multiple GNU_RELRO segments not supported".into());
                     }
                 }
                 Type::Null
-                | Type::Dynamic
                 | Type::Interp
                 | Type::Note
                 | Type::ShLib
                 | Type::Phdr
-                | Type::GnuRelro
                 | Type::OsSpecific(_)
                 | Type::ProcessorSpecific(_) => {}
             }
         }
-        Ok(tls_template)
+
+        // relocations must be applied after all segments are mapped
+        let is_pie = matches!(
+            self.elf_file.header.pt2.type_().as_type(),
+            header::Type::SharedObject
+        );
+        if let (true, Some(segment)) = (is_pie, dynamic_segment) {
+            self.inner.handle_dynamic_segment(segment, &self.elf_file)?;
+        }
+
+        // the RELRO region must only be made read-only after relocations are applied
+        if let Some(segment) = relro_segment {
+            self.inner.handle_relro_segment(segment)?;
+        }
+
+        // the frames of the segments are only final after all modifications, which might have
+        // copied frames of the kernel file (e.g. for relocations or `.bss` sections)
+        for segment in &mut segments.segments[..segments.len] {
+            segment.phys_addr = self
+                .inner
+                .page_table
+                .translate_addr(VirtAddr::new(segment.virt_addr))
+                .map_or(0, |addr| addr.as_u64());
+        }
+
+        Ok((tls_template, segments))
+    }
+
+    fn symbol_tables(&self) -> Result<Option<SymbolTables>, &'static str> {
+        let symtab = match self
+            .elf_file
+            .section_iter()
+            .find(|section| matches!(section.get_type(), Ok(ShType::SymTab)))
+        {
+            Some(symtab) => symtab,
+            None => return Ok(None),
+        };
+        // the `link` field of a symbol table contains the index of its string table
+        let strtab_index = symtab
+            .link()
+            .try_into()
+            .map_err(|_| "invalid string table index")?;
+        let strtab = self.elf_file.section_header(strtab_index)?;
+        if !matches!(strtab.get_type(), Ok(ShType::StrTab)) {
+            return Err("symbol table is not linked to a string table");
+        }
+
+        Ok(Some(SymbolTables {
+            symtab: section_range(&self.elf_file, symtab)?,
+            strtab: section_range(&self.elf_file, strtab)?,
+        }))
     }
 
     fn entry_point(&self) -> VirtAddr {
-        VirtAddr::new(self.elf_file.header.pt2.entry_point())
+        VirtAddr::new(self.elf_file.header.pt2.entry_point() + self.inner.virtual_address_offset)
     }
 
     fn used_level_4_entries(&self) -> UsedLevel4Entries {
-        UsedLevel4Entries::new(self.elf_file.program_iter())
+        UsedLevel4Entries::new(
+            self.elf_file.program_iter(),
+            self.inner.virtual_address_offset,
+        )
     }
 }
 
 impl<'a, M, F> Inner<'a, M, F>
 where
-    M: MapperAllSizes,
-    F: FrameAllocator<Size4KiB>,
+    M: MapperAllSizes + Translate,
+    F: FrameAllocator<Size4KiB> + FrameAllocator<Size2MiB>,
 {
-    fn handle_load_segment(&mut self, segment: ProgramHeader) -> Result<(), &'static str> {
+    fn handle_load_segment(&mut self, segment: ProgramHeader) -> Result<(), LoadKernelError> {
         log::info!("Handling Segment: {:x?}", segment);
 
         let phys_start_addr = self.kernel_offset + segment.offset();
@@ -106,7 +304,7 @@ where
         let end_frame: PhysFrame =
             PhysFrame::containing_address(phys_start_addr + segment.file_size() - 1u64);
 
-        let virt_start_addr = VirtAddr::new(segment.virtual_addr());
+        let virt_start_addr = VirtAddr::new(segment.virtual_addr() + self.virtual_address_offset);
         let start_page: Page = Page::containing_address(virt_start_addr);
 
         let mut segment_flags = Flags::PRESENT;
@@ -118,16 +316,38 @@ where
         }
 
         // map all frames of the segment at the desired virtual address
-        for frame in PhysFrame::range_inclusive(start_frame, end_frame) {
-            let offset = frame - start_frame;
+        let file_end = virt_start_addr + segment.file_size();
+        let frame_count = end_frame - start_frame + 1;
+        let mut offset = 0;
+        while offset < frame_count {
             let page = start_page + offset;
+            let frame = start_frame + offset;
+
+            // use a 2MiB page if the chunk is completely backed by file data
+            if let Ok(huge_page) = Page::<Size2MiB>::from_start_address(page.start_address()) {
+                let chunk_end = huge_page.start_address() + Size2MiB::SIZE;
+                if huge_page.start_address() >= virt_start_addr && chunk_end <= file_end {
+                    if let Some(huge_frame) = self.huge_frame_for(frame)? {
+                        self.map_huge_page(huge_page, huge_frame, segment_flags)?;
+                        offset += Size2MiB::SIZE / Size4KiB::SIZE;
+                        continue;
+                    }
+                }
+            }
+
+            let frame = if CONFIG.copy_kernel_segments {
+                self.copy_frame(frame)?
+            } else {
+                frame
+            };
             let flusher = unsafe {
                 self.page_table
                     .map_to(page, frame, segment_flags, self.frame_allocator)
-                    .map_err(|_err| "map_to failed")?
+                    .map_err(|err| map_to_error(page, err, "map_to failed"))?
             };
             // we operate on an inactive page table, so there's no need to flush anything
             flusher.ignore();
+            offset += 1;
         }
 
         // Handle .bss section (mem_size > file_size)
@@ -139,14 +359,88 @@ where
         Ok(())
     }
 
+    /// Returns a 2MiB frame containing the 2MiB of file data that starts at the given frame.
+    ///
+    /// If the data is not 2MiB-aligned in the loaded ELF file, it is copied to a newly allocated
+    /// 2MiB frame if the `kernel-huge-pages` config option is enabled. Otherwise, `None` is
+    /// returned, which means that the data must be mapped with 4KiB pages.
+    ///
+    /// If the `copy-kernel-segments` config option is enabled, the data is always copied. In
+    /// this case, `None` is also returned if no 2MiB frame is available.
+    fn huge_frame_for(
+        &mut self,
+        frame: PhysFrame,
+    ) -> Result<Option<PhysFrame<Size2MiB>>, &'static str> {
+        if !CONFIG.copy_kernel_segments {
+            if let Ok(huge_frame) = PhysFrame::from_start_address(frame.start_address()) {
+                return Ok(Some(huge_frame));
+            }
+            if !CONFIG.kernel_huge_pages {
+                return Ok(None);
+            }
+        }
+
+        let huge_frame: Option<PhysFrame<Size2MiB>> = self.frame_allocator.allocate_frame();
+        let huge_frame = match huge_frame {
+            Some(huge_frame) => huge_frame,
+            // copy the data into 4KiB frames instead
+            None if !CONFIG.kernel_huge_pages => return Ok(None),
+            None => return Err("Failed to allocate 2MiB frame for kernel segment"),
+        };
+        // copy the data, utilizing the identity-mapping
+        unsafe {
+            ptr::copy_nonoverlapping(
+                frame.start_address().as_u64() as *const u8,
+                huge_frame.start_address().as_u64() as *mut u8,
+                Size2MiB::SIZE as usize,
+            )
+        };
+        Ok(Some(huge_frame))
+    }
+
+    /// Copies the contents of the given frame to a newly allocated frame.
+    fn copy_frame(&mut self, frame: PhysFrame) -> Result<PhysFrame, &'static str> {
+        let new_frame: PhysFrame = self
+            .frame_allocator
+            .allocate_frame()
+            .ok_or("Failed to allocate frame for kernel segment")?;
+        // copy the frame contents, utilizing the identity-mapping
+        unsafe {
+            ptr::copy_nonoverlapping(
+                frame.start_address().as_u64() as *const u8,
+                new_frame.start_address().as_u64() as *mut u8,
+                Size4KiB::SIZE as usize,
+            )
+        };
+        Ok(new_frame)
+    }
+
+    fn map_huge_page(
+        &mut self,
+        page: Page<Size2MiB>,
+        frame: PhysFrame<Size2MiB>,
+        flags: Flags,
+    ) -> Result<(), LoadKernelError> {
+        let flusher = unsafe {
+            self.page_table
+                .map_to(page, frame, flags, self.frame_allocator)
+                .map_err(|err| {
+                    map_to_error(page, err, "Failed to map 2MiB page of kernel segment")
+                })?
+        };
+        // we operate on an inactive page table, so there's no need to flush anything
+        flusher.ignore();
+        Ok(())
+    }
+
     fn handle_bss_section(
         &mut self,
         segment: &ProgramHeader,
         segment_flags: Flags,
-    ) -> Result<(), &'static str> {
+    ) -> Result<(), LoadKernelError> {
         log::info!("Mapping bss section");
 
-        let virt_start_addr = VirtAddr::new(segment.virtual_addr());
+        let virt_start_addr = VirtAddr::new(segment.virtual_addr() + self.virtual_address_offset);
         let phys_start_addr = self.kernel_offset + segment.offset();
         let mem_size = segment.mem_size();
         let file_size = segment.file_size();
@@ -196,7 +490,7 @@ where
             let orig_frame: PhysFrame =
                 PhysFrame::containing_address(phys_start_addr + file_size - 1u64);
             // allocate a new frame to replace `orig_frame`
-            let new_frame = self.frame_allocator.allocate_frame().unwrap();
+            let new_frame: PhysFrame = self.frame_allocator.allocate_frame().unwrap();
 
             // zero new frame, utilizing that it's identity-mapped
             {
@@ -239,9 +533,32 @@ where
         let start_page: Page =
             Page::containing_address(VirtAddr::new(align_up(zero_start.as_u64(), Size4KiB::SIZE)));
         let end_page = Page::containing_address(zero_end);
-        for page in Page::range_inclusive(start_page, end_page) {
+        let mut page = start_page;
+        while page <= end_page {
+            // use a zeroed 2MiB frame if a complete 2MiB chunk needs to be mapped
+            if let Ok(huge_page) = Page::<Size2MiB>::from_start_address(page.start_address()) {
+                let chunk_end = huge_page.start_address() + Size2MiB::SIZE;
+                if chunk_end <= end_page.start_address() + Size4KiB::SIZE {
+                    let huge_frame: Option<PhysFrame<Size2MiB>> =
+                        self.frame_allocator.allocate_frame();
+                    if let Some(huge_frame) = huge_frame {
+                        // zero frame, utilizing identity-mapping
+                        unsafe {
+                            ptr::write_bytes(
+                                huge_frame.start_address().as_u64() as *mut u8,
+                                0,
+                                Size2MiB::SIZE as usize,
+                            )
+                        };
+                        self.map_huge_page(huge_page, huge_frame, segment_flags)?;
+                        page += Size2MiB::SIZE / Size4KiB::SIZE;
+                        continue;
+                    }
+                }
+            }
+
             // allocate a new unused frame
-            let frame = self.frame_allocator.allocate_frame().unwrap();
+            let frame: PhysFrame = self.frame_allocator.allocate_frame().unwrap();
 
             // zero frame, utilizing identity-mapping
             let frame_ptr = frame.start_address().as_u64() as *mut PageArray;
@@ -251,10 +568,13 @@ where
             let flusher = unsafe {
                 self.page_table
                     .map_to(page, frame, segment_flags, self.frame_allocator)
-                    .map_err(|_err| "Failed to map new frame for bss memory")?
+                    .map_err(|err| {
+                        map_to_error(page, err, "Failed to map new frame for bss memory")
+                    })?
             };
             // we operate on an inactive page table, so we don't need to flush our changes
             flusher.ignore();
+            page += 1;
         }
 
         Ok(())
@@ -262,25 +582,339 @@ where
 
     fn handle_tls_segment(&mut self, segment: ProgramHeader) -> Result<TlsTemplate, &'static str> {
         Ok(TlsTemplate {
-            start_addr: segment.virtual_addr(),
+            start_addr: segment.virtual_addr() + self.virtual_address_offset,
             mem_size: segment.mem_size(),
             file_size: segment.file_size(),
+            align: segment.align(),
         })
     }
+
+    /// Remaps the memory covered by the given `PT_GNU_RELRO` segment as read-only.
+    ///
+    /// The RELRO region is writable only for applying relocations, so it must be remapped
+    /// after all relocations are applied. Like glibc's dynamic linker, we round both the start
+    /// and the end of the segment down to page boundaries, so the page that contains the start
+    /// is remapped too, while a partially covered last page stays writable.
+    fn handle_relro_segment(&mut self, segment: ProgramHeader) -> Result<(), &'static str> {
+        log::info!("Handling RELRO segment: {:x?}", segment);
+
+        let start = VirtAddr::new(segment.virtual_addr() + self.virtual_address_offset);
+        let end = (start + segment.mem_size()).align_down(Size4KiB::SIZE);
+        let mut page: Page = Page::containing_address(start);
+        while page.start_address() < end {
+            match self.page_table.translate(page.start_address()) {
+                TranslateResult::Mapped {
+                    frame: MappedFrame::Size4KiB(_),
+                    flags,
+                    ..
+                } => {
+                    unsafe { self.page_table.update_flags(page, flags & !Flags::WRITABLE) }
+                        .map_err(|_err| "Failed to update flags of RELRO page")?
+                        .ignore();
+                    page += 1;
+                }
+                TranslateResult::Mapped {
+                    frame: MappedFrame::Size2MiB(huge_frame),
+                    flags,
+                    ..
+                } => {
+                    let huge_page = Page::<Size2MiB>::containing_address(page.start_address());
+                    if huge_page.start_address() == page.start_address()
+                        && huge_page.start_address() + Size2MiB::SIZE <= end
+                    {
+                        unsafe {
+                            self.page_table
+                                .update_flags(huge_page, flags & !Flags::WRITABLE)
+                        }
+                        .map_err(|_err| "Failed to update flags of RELRO page")?
+                        .ignore();
+                        page += Size2MiB::SIZE / Size4KiB::SIZE;
+                    } else {
+                        // only part of the 2MiB page is covered by the RELRO region
+                        self.split_huge_page(huge_page, huge_frame, flags)?;
+                    }
+                }
+                TranslateResult::Mapped { .. } => return Err("RELRO page is a 1GiB page"),
+                _ => return Err("RELRO page is not mapped"),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Applies the relocations of the `.rela.dyn` table that the given dynamic segment points to.
+    fn handle_dynamic_segment(
+        &mut self,
+        segment: ProgramHeader,
+        elf_file: &ElfFile,
+    ) -> Result<(), &'static str> {
+        let data = match segment.get_data(elf_file)? {
+            SegmentData::Dynamic64(data) => data,
+            _ => return Err("expected 64-bit dynamic segment"),
+        };
+
+        let mut rela = None;
+        let mut rela_size = None;
+        let mut rela_ent = None;
+        let mut sym_tab = None;
+        let mut sym_ent = None;
+        for entry in data {
+            match entry.get_tag()? {
+                dynamic::Tag::Rela => rela = Some(entry.get_ptr()?),
+                dynamic::Tag::RelaSize => rela_size = Some(entry.get_val()?),
+                dynamic::Tag::RelaEnt => rela_ent = Some(entry.get_val()?),
+                dynamic::Tag::SymTab => sym_tab = Some(entry.get_ptr()?),
+                dynamic::Tag::SymEnt => sym_ent = Some(entry.get_val()?),
+                dynamic::Tag::Rel | dynamic::Tag::Relr | dynamic::Tag::JmpRel => {
+                    return Err("only `.rela.dyn` relocations are supported")
+                }
+                _ => {}
+            }
+        }
+
+        let rela = match rela {
+            Some(rela) => rela,
+            // the kernel has no relocations
+            None => return Ok(()),
+        };
+        let rela_size = rela_size.ok_or("dynamic segment has no RELASZ entry")?;
+        if rela_ent != Some(mem::size_of::<Rela<u64>>() as u64) {
+            return Err("invalid or missing RELAENT entry in dynamic segment");
+        }
+        let relocations: &[Rela<u64>] = read_table(elf_file, rela, rela_size)?;
+
+        // looks up the relocated value of the symbol with the given index
+        let virtual_address_offset = self.virtual_address_offset;
+        let symbol_value = |index: u32| -> Result<u64, &'static str> {
+            let sym_tab = sym_tab.ok_or("dynamic segment has no SYMTAB entry")?;
+            if sym_ent != Some(mem::size_of::<DynEntry64>() as u64) {
+                return Err("invalid or missing SYMENT entry in dynamic segment");
+            }
+            let entry_offset = sym_tab + u64::from(index) * mem::size_of::<DynEntry64>() as u64;
+            let symbol = &read_table::<DynEntry64>(elf_file, entry_offset, 1)?[0];
+            match symbol.shndx() {
+                // like dynamic linkers, resolve undefined weak symbols to zero
+                SHN_UNDEF if symbol.get_binding()? == Binding::Weak => Ok(0),
+                SHN_UNDEF => Err("relocation against undefined symbol"),
+                SHN_ABS => Ok(symbol.value()),
+                _ => Ok(symbol.value() + virtual_address_offset),
+            }
+        };
+
+        for rela in relocations {
+            let value = match rela.get_type() {
+                R_X86_64_NONE => continue,
+                R_X86_64_RELATIVE => virtual_address_offset.wrapping_add(rela.get_addend()),
+                R_X86_64_64 => {
+                    symbol_value(rela.get_symbol_table_index())?.wrapping_add(rela.get_addend())
+                }
+                R_X86_64_GLOB_DAT => symbol_value(rela.get_symbol_table_index())?,
+                _ => return Err("unsupported relocation type"),
+            };
+            let addr = VirtAddr::new(rela.get_offset() + virtual_address_offset);
+            self.write_kernel_memory(addr, &value.to_le_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the given bytes to the given virtual address of the kernel address space.
+    ///
+    /// The bytes are split at page boundaries, so that each page is only looked up once.
+    fn write_kernel_memory(
+        &mut self,
+        mut addr: VirtAddr,
+        mut bytes: &[u8],
+    ) -> Result<(), &'static str> {
+        while !bytes.is_empty() {
+            let page_offset = u64::from(addr.page_offset());
+            let chunk_len = bytes.len().min((Size4KiB::SIZE - page_offset) as usize);
+            let (chunk, rest) = bytes.split_at(chunk_len);
+
+            let frame = self.make_mut(Page::containing_address(addr))?;
+            let phys_addr = frame.start_address() + page_offset;
+            // utilize the identity-mapping
+            unsafe {
+                ptr::copy_nonoverlapping(chunk.as_ptr(), phys_addr.as_u64() as *mut u8, chunk_len)
+            };
+
+            addr += chunk_len;
+            bytes = rest;
+        }
+        Ok(())
+    }
+
+    /// Returns the frame that the given kernel page is mapped to, after ensuring that this frame
+    /// is not part of the kernel ELF file.
+    ///
+    /// The frames of the ELF file might be shared by multiple segments (see the comment in
+    /// `handle_bss_section`), so we must not modify them. Instead, we copy their contents to a
+    /// new frame and remap the page.
+    fn make_mut(&mut self, page: Page) -> Result<PhysFrame, &'static str> {
+        let elf_start = self.kernel_offset;
+        let elf_end = self.kernel_offset + self.kernel_len;
+        let in_elf_file = |addr: PhysAddr| addr >= elf_start && addr < elf_end;
+
+        let (frame, flags) = match self.page_table.translate(page.start_address()) {
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size4KiB(frame),
+                flags,
+                ..
+            } => (frame, flags),
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size2MiB(huge_frame),
+                flags,
+                ..
+            } => {
+                let huge_page = Page::<Size2MiB>::containing_address(page.start_address());
+                let offset = page.start_address() - huge_page.start_address();
+                if !in_elf_file(huge_frame.start_address()) {
+                    // frame is already private to this page
+                    return Ok(PhysFrame::containing_address(
+                        huge_frame.start_address() + offset,
+                    ));
+                }
+                // split the 2MiB page into 4KiB pages, so that we only need to copy one of them
+                self.split_huge_page(huge_page, huge_frame, flags)?;
+                return self.make_mut(page);
+            }
+            TranslateResult::Mapped { .. } => return Err("relocation target is a 1GiB page"),
+            _ => return Err("relocation target is not mapped"),
+        };
+        if !in_elf_file(frame.start_address()) {
+            // frame is already private to this page
+            return Ok(frame);
+        }
+
+        let new_frame = self.copy_frame(frame)?;
+
+        self.page_table
+            .unmap(page)
+            .map_err(|_err| "Failed to unmap kernel page")?
+            .1
+            .ignore();
+        let flusher = unsafe {
+            self.page_table
+                .map_to(page, new_frame, flags, self.frame_allocator)
+                .map_err(|_err| "Failed to remap kernel page")?
+        };
+        // we operate on an inactive page table, so we don't need to flush our changes
+        flusher.ignore();
+
+        Ok(new_frame)
+    }
+
+    /// Remaps the given 2MiB page to the same physical memory using 4KiB pages.
+    fn split_huge_page(
+        &mut self,
+        huge_page: Page<Size2MiB>,
+        huge_frame: PhysFrame<Size2MiB>,
+        flags: Flags,
+    ) -> Result<(), &'static str> {
+        self.page_table
+            .unmap(huge_page)
+            .map_err(|_err| "Failed to unmap 2MiB page")?
+            .1
+            .ignore();
+
+        let start_page: Page = Page::containing_address(huge_page.start_address());
+        let start_frame: PhysFrame = PhysFrame::containing_address(huge_frame.start_address());
+        for offset in 0..(Size2MiB::SIZE / Size4KiB::SIZE) {
+            let flusher = unsafe {
+                self.page_table
+                    .map_to(
+                        start_page + offset,
+                        start_frame + offset,
+                        flags & !Flags::HUGE_PAGE,
+                        self.frame_allocator,
+                    )
+                    .map_err(|_err| "Failed to remap 2MiB page with 4KiB pages")?
+            };
+            // we operate on an inactive page table, so we don't need to flush our changes
+            flusher.ignore();
+        }
+        Ok(())
+    }
+}
+
+/// Returns the byte range of the given section within the ELF file.
+fn section_range(elf_file: &ElfFile, section: SectionHeader) -> Result<Range<usize>, &'static str> {
+    let start = section.offset() as usize;
+    let end = start + section.size() as usize;
+    if end > elf_file.input.len() {
+        return Err("section is out of bounds");
+    }
+    Ok(start..end)
+}
+
+/// Returns the table of `size` bytes at the given virtual address of the ELF file.
+///
+/// The virtual address is translated to a file offset using the loadable segments.
+fn read_table<'a, T>(
+    elf_file: &ElfFile<'a>,
+    virt_addr: u64,
+    size: u64,
+) -> Result<&'a [T], &'static str> {
+    let segment = elf_file
+        .program_iter()
+        .filter(|segment| matches!(segment.get_type(), Ok(Type::Load)))
+        .find(|segment| {
+            segment.virtual_addr() <= virt_addr
+                && virt_addr + size <= segment.virtual_addr() + segment.file_size()
+        })
+        .ok_or("dynamic table is not part of a loadable segment")?;
+    let offset = (segment.offset() + virt_addr - segment.virtual_addr()) as usize;
+    let bytes = &elf_file.input[offset..offset + size as usize];
+    if bytes.as_ptr() as usize % mem::align_of::<T>() != 0 {
+        return Err("dynamic table is not sufficiently aligned");
+    }
+    let len = bytes.len() / mem::size_of::<T>();
+    Ok(unsafe { slice::from_raw_parts(bytes.as_ptr() as *const T, len) })
+}
+
+/// Converts an error of a `map_to` call for the given page into a [`LoadKernelError`].
+///
+/// Reports already mapped pages as [`LoadKernelError::AddressInUse`] since they indicate that
+/// the kernel overlaps other mappings, e.g. the identity-mapped bootloader in the first level 4
+/// entry.
+fn map_to_error<S: PageSize>(
+    page: Page<S>,
+    err: MapToError<S>,
+    message: &'static str,
+) -> LoadKernelError {
+    match err {
+        MapToError::PageAlreadyMapped(_) => LoadKernelError::AddressInUse {
+            addr: page.start_address().as_u64(),
+        },
+        _ => LoadKernelError::Load(message),
+    }
 }
 
 /// Loads the kernel ELF file given in `bytes` in the given `page_table`.
 ///
-/// Returns the kernel entry point address, it's thread local storage template (if any),
-/// and a structure describing which level 4 page table entries are in use.  
+/// Returns information about the loaded kernel, including the structure describing which
+/// level 4 page table entries are in use.
+///
+/// The kernel is validated using [`check_kernel`] before it is loaded.
 pub fn load_kernel(
     bytes: &[u8],
-    page_table: &mut impl MapperAllSizes,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) -> Result<(VirtAddr, Option<TlsTemplate>, UsedLevel4Entries), &'static str> {
+    page_table: &mut (impl MapperAllSizes + Translate),
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameAllocator<Size2MiB>),
+) -> Result<LoadedKernel, LoadKernelError> {
     let mut loader = Loader::new(bytes, page_table, frame_allocator)?;
-    let tls_template = loader.load_segments()?;
-    let used_entries = loader.used_level_4_entries();
-
-    Ok((loader.entry_point(), tls_template, used_entries))
+    let (tls_template, segments) = loader.load_segments()?;
+    let symbols = if CONFIG.keep_symbols {
+        loader.symbol_tables()?
+    } else {
+        None
+    };
+
+    Ok(LoadedKernel {
+        entry_point: loader.entry_point(),
+        tls_template,
+        used_entries: loader.used_level_4_entries(),
+        kernel_base: loader.inner.virtual_address_offset,
+        segments,
+        symbols,
+    })
 }
@@ -1,21 +1,26 @@
 use crate::{
     binary::legacy_memory_region::{LegacyFrameAllocator, LegacyMemoryRegion},
     boot_info::{
-        BootInfo, FrameBuffer, FrameBufferInfo, MemoryRegion, Module, Modules, TlsTemplate,
+        BootInfo, FfiStr, FrameBuffer, FrameBufferInfo, KernelSegment, KernelStack, KernelSymbols,
+        MemoryRegion, Module, Modules, TlsBlock, TlsTemplate, BOOT_MAGIC,
     },
 };
 use core::{
     arch::asm,
+    fmt::Write,
     mem::{self, MaybeUninit},
-    slice,
+    ptr, slice,
 };
 use level_4_entries::UsedLevel4Entries;
 use parsed_config::CONFIG;
+use sha2::{Digest, Sha256};
 use usize_conversions::FromUsize;
 use x86_64::{
+    align_up,
+    registers::model_specific::FsBase,
     structures::paging::{
         FrameAllocator, Mapper, OffsetPageTable, Page, PageTableFlags, PageTableIndex, PhysFrame,
-        Size2MiB,
+        Size2MiB, Translate,
     },
     PhysAddr, VirtAddr,
 };
@@ -23,19 +28,29 @@ use x86_64::{
 /// Provides BIOS-specific types and trait implementations.
 #[cfg(feature = "bios_bin")]
 pub mod bios;
-/// Provides UEFI-specific trait implementations.
+/// Provides UEFI-specific constants and trait implementations.
 #[cfg(feature = "uefi_bin")]
-mod uefi;
+pub mod uefi;
 
+/// Implements decompression of compressed kernel and module payloads.
+pub mod compression;
+/// Provides a random number generator for kernel address space layout randomization.
+pub mod entropy;
 mod gdt;
 /// Provides a frame allocator based on a BIOS or UEFI memory map.
 pub mod legacy_memory_region;
 /// Provides a type to keep track of used entries in a level 4 page table.
 pub mod level_4_entries;
+/// Implements booting Linux kernel images using the x86 boot protocol.
+pub mod linux;
 /// Implements a loader for the kernel ELF binary.
 pub mod load_kernel;
 /// Provides a logger type that logs output as text to pixel-based framebuffers.
 pub mod logger;
+/// Implements booting kernels using the Multiboot2 protocol.
+pub mod multiboot2;
+/// Provides a writer for reporting fatal errors on the serial port.
+pub mod serial;
 
 // Contains the parsed configuration table from the kernel's Cargo.toml.
 //
@@ -52,6 +67,9 @@ include!(concat!(env!("OUT_DIR"), "/bootloader_config.rs"));
 
 const PAGE_SIZE: u64 = 4096;
 
+/// The pattern that the kernel stack is filled with if the `kernel-stack-canary` option is set.
+const KERNEL_STACK_CANARY: u64 = 0x57ac_c0de_57ac_c0de;
+
 /// Initialize a text-based logger using the given pixel-based framebuffer as output.  
 pub fn init_logger(framebuffer: &'static mut [u8], info: FrameBufferInfo) {
     let logger = logger::LOGGER.get_or_init(move || logger::LockedLogger::new(framebuffer, info));
@@ -60,6 +78,15 @@ pub fn init_logger(framebuffer: &'static mut [u8], info: FrameBufferInfo) {
     log::info!("Framebuffer info: {:?}", info);
 }
 
+/// Reports that the kernel could not be loaded and panics.
+///
+/// The panic message is only shown on the framebuffer, so the error is reported on the serial
+/// port too.
+pub fn kernel_load_failed(err: load_kernel::LoadKernelError) -> ! {
+    let _ = writeln!(serial::SerialPort::com1(), "Failed to load kernel: {}", err);
+    panic!("Failed to load kernel: {}", err)
+}
+
 /// Required system information that should be queried from the BIOS or UEFI firmware.
 #[derive(Debug, Copy, Clone)]
 pub struct SystemInfo {
@@ -76,6 +103,10 @@ pub struct SystemInfo {
 /// This function is a convenience function that first calls [`set_up_mappings`], then
 /// [`create_boot_info`], and finally [`switch_to_kernel`]. The given arguments are passed
 /// directly to these functions, so see their docs for more info.
+///
+/// Kernels with a Multiboot2 header are booted through [`multiboot2::load_and_switch_to_kernel`]
+/// instead, which ignores the given page tables. Linux kernel images are booted through
+/// [`linux::load_and_switch_to_kernel`].
 pub fn load_and_switch_to_kernel<I, D>(
     kernel_bytes: &[u8],
     mut frame_allocator: LegacyFrameAllocator<I, D>,
@@ -87,6 +118,19 @@ where
     I: ExactSizeIterator<Item = D> + Clone,
     D: LegacyMemoryRegion,
 {
+    verify_modules(&modules);
+    if let Some(kernel) = multiboot2::detect(kernel_bytes) {
+        multiboot2::load_and_switch_to_kernel(&kernel, frame_allocator, system_info, &modules);
+    }
+    if let Some(kernel) = linux::detect(kernel_bytes) {
+        linux::load_and_switch_to_kernel(
+            &kernel,
+            frame_allocator,
+            page_tables,
+            system_info,
+            &modules,
+        );
+    }
     let mut mappings = set_up_mappings(
         kernel_bytes,
         &mut frame_allocator,
@@ -104,6 +148,27 @@ where
     switch_to_kernel(page_tables, mappings, boot_info);
 }
 
+/// Verifies the contents of the loaded modules against their SHA-256 digests.
+///
+/// The digests are computed by the build script from the original module files, so a mismatch
+/// means that the module was corrupted on the boot medium or while loading it. This function
+/// must be called while the physical memory is still identity-mapped.
+pub fn verify_modules(modules: &[Module]) {
+    for module in modules {
+        let bytes = unsafe { slice::from_raw_parts(module.phys_addr as *const u8, module.len) };
+        let digest: [u8; 32] = Sha256::digest(bytes).into();
+        if digest != module.sha256 {
+            panic!(
+                "module `{}` does not match its SHA-256 digest (corrupted boot medium?)",
+                &*module.name
+            );
+        }
+    }
+    if !modules.is_empty() {
+        log::info!("Verified SHA-256 digests of {} modules", modules.len());
+    }
+}
+
 /// Sets up mappings for a kernel stack and the framebuffer.
 ///
 /// The `kernel_bytes` slice should contain the raw bytes of the kernel ELF executable. The
@@ -136,22 +201,65 @@ where
     // Make the kernel respect the write-protection bits even when in ring 0 by default
     enable_write_protect_bit();
 
-    let (entry_point, tls_template, mut used_entries) =
-        load_kernel::load_kernel(kernel_bytes, kernel_page_table, frame_allocator)
-            .expect("no entry point");
+    let load_kernel::LoadedKernel {
+        entry_point,
+        tls_template,
+        mut used_entries,
+        kernel_base,
+        segments: kernel_segments,
+        symbols,
+    } = load_kernel::load_kernel(kernel_bytes, kernel_page_table, frame_allocator)
+        .unwrap_or_else(|err| kernel_load_failed(err));
     log::info!("Entry point at: {:#x}", entry_point.as_u64());
 
-    // create a stack
-    let stack_start_addr = kernel_stack_start_location(&mut used_entries);
+    // the kernel bytes are identity-mapped
+    let kernel_start = PhysAddr::new(kernel_bytes.as_ptr() as u64);
+    let kernel_end = kernel_start + kernel_bytes.len();
+    if CONFIG.copy_kernel_segments {
+        // the kernel no longer references the ELF file, so its memory can be reused
+        frame_allocator.set_kernel_file_region(
+            kernel_start.align_up(PAGE_SIZE),
+            kernel_end.align_down(PAGE_SIZE),
+        );
+    }
+
+    // create a stack with an unmapped guard region below it
+    let guard_size = CONFIG.kernel_stack_guard_size.unwrap_or(PAGE_SIZE);
+    let stack_start_addr = kernel_stack_start_location(&mut used_entries, guard_size);
     let stack_start: Page = Page::containing_address(stack_start_addr);
     let stack_end = {
         let end_addr = stack_start_addr + CONFIG.kernel_stack_size.unwrap_or(20 * PAGE_SIZE);
         Page::containing_address(end_addr - 1u64)
     };
+    let guard_start = stack_start_addr
+        .as_u64()
+        .checked_sub(guard_size)
+        .map(VirtAddr::new)
+        .expect("kernel stack guard region is out of bounds");
+    for page in Page::range(Page::containing_address(guard_start), stack_start) {
+        if kernel_page_table
+            .translate_addr(page.start_address())
+            .is_some()
+        {
+            panic!("kernel stack guard page {:?} is already mapped", page);
+        }
+    }
+    // keep later mappings out of the guard region, e.g. for a configured `kernel-stack-address`
+    used_entries.mark_range_as_used(guard_start, stack_end.start_address() + (PAGE_SIZE - 1));
     for page in Page::range_inclusive(stack_start, stack_end) {
         let frame = frame_allocator
             .allocate_frame()
             .expect("frame allocation failed when mapping a kernel stack");
+        if CONFIG.kernel_stack_canary {
+            // fill the frame with the canary pattern, utilizing the identity-mapping
+            let words = unsafe {
+                slice::from_raw_parts_mut(
+                    frame.start_address().as_u64() as *mut u64,
+                    (PAGE_SIZE / 8) as usize,
+                )
+            };
+            words.fill(KERNEL_STACK_CANARY);
+        }
         let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
         match unsafe { kernel_page_table.map_to(page, frame, flags, frame_allocator) } {
             Ok(tlb) => tlb.flush(),
@@ -242,6 +350,37 @@ where
         None
     };
 
+    let kernel_symbols = match symbols {
+        Some(symbols) => {
+            log::info!("Map kernel symbols");
+            Some(map_kernel_symbols(
+                &kernel_bytes[symbols.symtab],
+                &kernel_bytes[symbols.strtab],
+                kernel_page_table,
+                frame_allocator,
+                &mut used_entries,
+            ))
+        }
+        None if CONFIG.keep_symbols => {
+            log::warn!("Kernel has no symbol table");
+            None
+        }
+        None => None,
+    };
+
+    let tls_block = match tls_template {
+        Some(template) if CONFIG.map_tls_block => {
+            log::info!("Map TLS block");
+            Some(map_tls_block(
+                template,
+                kernel_page_table,
+                frame_allocator,
+                &mut used_entries,
+            ))
+        }
+        _ => None,
+    };
+
     let recursive_index = if CONFIG.map_page_table_recursively {
         log::info!("Map page table recursively");
         let index = CONFIG
@@ -267,11 +406,26 @@ where
     Mappings {
         framebuffer: framebuffer_virt_addr,
         entry_point,
+        kernel_base,
+        kernel_addr: kernel_start,
+        kernel_len: kernel_end - kernel_start,
+        kernel_segments,
+        kernel_symbols,
         stack_end,
+        kernel_stack: KernelStack {
+            bottom: stack_start.start_address().as_u64(),
+            top: stack_end.start_address().as_u64(),
+            guard_size,
+            canary: CONFIG
+                .kernel_stack_canary
+                .then(|| KERNEL_STACK_CANARY)
+                .into(),
+        },
         used_entries,
         physical_memory_offset,
         recursive_index,
         tls_template,
+        tls_block,
     }
 }
 
@@ -279,8 +433,22 @@ where
 pub struct Mappings {
     /// The entry point address of the kernel.
     pub entry_point: VirtAddr,
+    /// The offset that was added to all virtual addresses of the kernel ELF file.
+    ///
+    /// Always zero for kernels that are not position-independent.
+    pub kernel_base: u64,
+    /// The physical start address of the loaded kernel ELF file.
+    pub kernel_addr: PhysAddr,
+    /// The length of the loaded kernel ELF file in bytes.
+    pub kernel_len: u64,
+    /// The loadable segments of the kernel.
+    pub kernel_segments: load_kernel::KernelSegmentList,
+    /// The location of the kernel's symbol and string tables, if enabled.
+    pub kernel_symbols: Option<KernelSymbols>,
     /// The stack end page of the kernel.
     pub stack_end: Page,
+    /// The location of the kernel stack, as reported to the kernel.
+    pub kernel_stack: KernelStack,
     /// Keeps track of used entries in the level 4 page table, useful for finding a free
     /// virtual memory when needed.
     pub used_entries: UsedLevel4Entries,
@@ -292,6 +460,8 @@ pub struct Mappings {
     pub recursive_index: Option<PageTableIndex>,
     /// The thread local storage template of the kernel executable, if it contains one.
     pub tls_template: Option<TlsTemplate>,
+    /// The thread local storage block of the bootstrap CPU, if enabled.
+    pub tls_block: Option<TlsBlock>,
 }
 
 /// Allocates and initializes the boot info struct and the memory map.
@@ -305,28 +475,49 @@ pub fn create_boot_info<I, D>(
     page_tables: &mut PageTables,
     mappings: &mut Mappings,
     system_info: SystemInfo,
-    modules_slice: Modules,
+    mut modules_slice: Modules,
 ) -> &'static mut BootInfo
 where
     I: ExactSizeIterator<Item = D> + Clone,
     D: LegacyMemoryRegion,
 {
+    if !modules_slice.is_empty() {
+        log::info!("Map modules");
+        map_modules(
+            &mut modules_slice,
+            &mut page_tables.kernel,
+            &mut frame_allocator,
+            &mut mappings.used_entries,
+        );
+    }
+
     log::info!("Allocate bootinfo");
 
     // allocate and map space for the boot info
-    let (boot_info, memory_regions, modules) = {
+    let (boot_info, memory_regions, kernel_segments, modules, mut module_strings) = {
         let boot_info_addr = boot_info_location(&mut mappings.used_entries);
         let boot_info_end = boot_info_addr + mem::size_of::<BootInfo>();
         let memory_map_regions_addr =
             boot_info_end.align_up(u64::from_usize(mem::align_of::<MemoryRegion>()));
-        let regions = frame_allocator.len() + 1; // one region might be split into used/unused
+        let regions = frame_allocator.memory_map_len();
         let memory_map_regions_end =
             memory_map_regions_addr + regions * mem::size_of::<MemoryRegion>();
-        let modules_addr = memory_map_regions_end.align_up(mem::align_of::<Module>() as u64);
+        let kernel_segments_addr =
+            memory_map_regions_end.align_up(mem::align_of::<KernelSegment>() as u64);
+        let kernel_segments_len = mappings.kernel_segments.as_slice().len();
+        let kernel_segments_end =
+            kernel_segments_addr + kernel_segments_len * mem::size_of::<KernelSegment>();
+        let modules_addr = kernel_segments_end.align_up(mem::align_of::<Module>() as u64);
         let modules_end = modules_addr + modules_slice.len() * mem::size_of::<Module>();
+        let module_strings_addr = modules_end;
+        let module_strings_len: usize = modules_slice
+            .iter()
+            .map(|m| m.name.len() + m.args.len())
+            .sum();
+        let module_strings_end = module_strings_addr + module_strings_len;
 
         let start_page = Page::containing_address(boot_info_addr);
-        let end_page = Page::containing_address(modules_end - 1u64);
+        let end_page = Page::containing_address(module_strings_end - 1u64);
         for page in Page::range_inclusive(start_page, end_page) {
             let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
             let frame = frame_allocator
@@ -355,9 +546,21 @@ where
             unsafe { &mut *boot_info_addr.as_mut_ptr() };
         let memory_regions: &'static mut [MaybeUninit<MemoryRegion>] =
             unsafe { slice::from_raw_parts_mut(memory_map_regions_addr.as_mut_ptr(), regions) };
+        let kernel_segments: &'static mut [MaybeUninit<KernelSegment>] = unsafe {
+            slice::from_raw_parts_mut(kernel_segments_addr.as_mut_ptr(), kernel_segments_len)
+        };
         let modules: &'static mut [MaybeUninit<Module>] =
             unsafe { slice::from_raw_parts_mut(modules_addr.as_mut_ptr(), modules_slice.len()) };
-        (boot_info, memory_regions, modules)
+        let module_strings: &'static mut [MaybeUninit<u8>] = unsafe {
+            slice::from_raw_parts_mut(module_strings_addr.as_mut_ptr(), module_strings_len)
+        };
+        (
+            boot_info,
+            memory_regions,
+            kernel_segments,
+            modules,
+            module_strings,
+        )
     };
 
     log::info!("Create Memory Map");
@@ -365,8 +568,15 @@ where
     // build memory map
     let memory_regions = frame_allocator.construct_memory_map(memory_regions);
 
-    // copy modules
+    let kernel_segments =
+        MaybeUninit::write_slice(kernel_segments, mappings.kernel_segments.as_slice());
+
+    // copy modules and their names and arguments
     let modules = MaybeUninit::write_slice_cloned(modules, &modules_slice);
+    for module in modules.iter_mut() {
+        module.name = copy_str(&module.name, &mut module_strings);
+        module.args = copy_str(&module.args, &mut module_strings);
+    }
 
     log::info!("Create bootinfo");
 
@@ -390,11 +600,224 @@ where
         rsdp_addr: system_info.rsdp_addr.map(|addr| addr.as_u64()).into(),
         tls_template: mappings.tls_template.into(),
         modules: modules.into(),
+        kernel_base: mappings.kernel_base,
+        kernel_addr: mappings.kernel_addr.as_u64(),
+        kernel_len: mappings.kernel_len,
+        kernel_segments: kernel_segments.into(),
+        kernel_entry: mappings.entry_point.as_u64(),
+        kernel_symbols: mappings.kernel_symbols.into(),
+        tls_block: mappings.tls_block.into(),
+        kernel_stack: mappings.kernel_stack,
     });
 
     boot_info
 }
 
+/// Copies the given string to the start of `buffer` and advances `buffer` behind it.
+fn copy_str(s: &str, buffer: &mut &'static mut [MaybeUninit<u8>]) -> FfiStr {
+    let (target, rest) = mem::take(buffer).split_at_mut(s.len());
+    *buffer = rest;
+    let copied = MaybeUninit::write_slice(target, s.as_bytes());
+    FfiStr {
+        ptr: copied.as_ptr(),
+        len: copied.len(),
+    }
+}
+
+/// Maps the contents of all modules read-only into the kernel address space.
+///
+/// The modules are mapped one after another, starting at a page-aligned virtual address. The
+/// `virt_addr` field of each module is updated accordingly.
+fn map_modules<I, D>(
+    modules: &mut [Module],
+    kernel_page_table: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut LegacyFrameAllocator<I, D>,
+    used_entries: &mut UsedLevel4Entries,
+) where
+    I: ExactSizeIterator<Item = D> + Clone,
+    D: LegacyMemoryRegion,
+{
+    let mut next_page = Page::containing_address(modules_location(used_entries));
+    for module in modules.iter_mut() {
+        let phys_addr = PhysAddr::new(module.phys_addr);
+        module.virt_addr = next_page.start_address().as_u64() + (phys_addr.as_u64() % PAGE_SIZE);
+        if module.len == 0 {
+            continue;
+        }
+
+        let start_frame: PhysFrame = PhysFrame::containing_address(phys_addr);
+        let end_frame = PhysFrame::containing_address(phys_addr + module.len - 1u64);
+        for frame in PhysFrame::range_inclusive(start_frame, end_frame) {
+            let flags = PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE;
+            match unsafe { kernel_page_table.map_to(next_page, frame, flags, frame_allocator) } {
+                Ok(tlb) => tlb.ignore(),
+                Err(err) => panic!(
+                    "failed to map page {:?} to frame {:?}: {:?}",
+                    next_page, frame, err
+                ),
+            }
+            next_page += 1;
+        }
+    }
+}
+
+/// Copies the given symbol and string tables to new frames and maps them read-only into the
+/// kernel address space.
+///
+/// The tables are copied because the kernel ELF file is not mapped into the kernel address
+/// space and might be reported as usable memory.
+fn map_kernel_symbols<I, D>(
+    symtab: &[u8],
+    strtab: &[u8],
+    kernel_page_table: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut LegacyFrameAllocator<I, D>,
+    used_entries: &mut UsedLevel4Entries,
+) -> KernelSymbols
+where
+    I: ExactSizeIterator<Item = D> + Clone,
+    D: LegacyMemoryRegion,
+{
+    let start_addr = used_entries.get_free_address();
+    // place the string table behind the symbol table
+    let strtab_offset = align_up(symtab.len() as u64, 8);
+    let tables = [(symtab, 0), (strtab, strtab_offset)];
+    let len = strtab_offset + strtab.len() as u64;
+
+    let start_page: Page = Page::containing_address(start_addr);
+    let end_page = Page::containing_address(start_addr + len.max(1) - 1u64);
+    for page in Page::range_inclusive(start_page, end_page) {
+        let frame: PhysFrame = frame_allocator
+            .allocate_frame()
+            .expect("frame allocation for kernel symbols failed");
+
+        // copy the overlapping parts of the tables, utilizing the identity-mapping
+        let frame_bytes = unsafe {
+            slice::from_raw_parts_mut(
+                frame.start_address().as_u64() as *mut u8,
+                PAGE_SIZE as usize,
+            )
+        };
+        frame_bytes.fill(0);
+        let page_offset = page.start_address() - start_addr;
+        for &(table, table_offset) in tables.iter() {
+            let start = table_offset.max(page_offset);
+            let end = (table_offset + table.len() as u64).min(page_offset + PAGE_SIZE);
+            if start < end {
+                frame_bytes[(start - page_offset) as usize..(end - page_offset) as usize]
+                    .copy_from_slice(
+                        &table[(start - table_offset) as usize..(end - table_offset) as usize],
+                    );
+            }
+        }
+
+        let flags = PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE;
+        match unsafe { kernel_page_table.map_to(page, frame, flags, frame_allocator) } {
+            Ok(tlb) => tlb.ignore(),
+            Err(err) => panic!(
+                "failed to map page {:?} to frame {:?}: {:?}",
+                page, frame, err
+            ),
+        }
+    }
+
+    KernelSymbols {
+        symtab_addr: start_addr.as_u64(),
+        symtab_len: symtab.len() as u64,
+        strtab_addr: start_addr.as_u64() + strtab_offset,
+        strtab_len: strtab.len() as u64,
+    }
+}
+
+/// Allocates a thread local storage (TLS) block for the bootstrap CPU and maps it into the
+/// kernel address space.
+///
+/// The block uses the variant II layout of the x86_64 System V ABI, i.e. the TLS data is placed
+/// directly below the thread pointer. The `.tdata` contents are copied from the loaded kernel,
+/// so that relocations of position-independent kernels are respected.
+fn map_tls_block<I, D>(
+    template: TlsTemplate,
+    kernel_page_table: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut LegacyFrameAllocator<I, D>,
+    used_entries: &mut UsedLevel4Entries,
+) -> TlsBlock
+where
+    I: ExactSizeIterator<Item = D> + Clone,
+    D: LegacyMemoryRegion,
+{
+    // the start address is aligned to a level 4 entry, so the thread pointer is suitably aligned
+    let start_addr = used_entries.get_free_address();
+    let thread_pointer = start_addr + align_up(template.mem_size, template.align.max(1));
+    // the thread control block only consists of the self pointer
+    let size = (thread_pointer - start_addr) + mem::size_of::<u64>() as u64;
+
+    let start_page: Page = Page::containing_address(start_addr);
+    let end_page = Page::containing_address(start_addr + size - 1u64);
+    for page in Page::range_inclusive(start_page, end_page) {
+        let frame: PhysFrame = frame_allocator
+            .allocate_frame()
+            .expect("frame allocation for TLS block failed");
+        // zero the frame, utilizing the identity-mapping (this also initializes `.tbss`)
+        unsafe {
+            ptr::write_bytes(
+                frame.start_address().as_u64() as *mut u8,
+                0,
+                PAGE_SIZE as usize,
+            )
+        };
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        match unsafe { kernel_page_table.map_to(page, frame, flags, frame_allocator) } {
+            Ok(tlb) => tlb.ignore(),
+            Err(err) => panic!(
+                "failed to map page {:?} to frame {:?}: {:?}",
+                page, frame, err
+            ),
+        }
+    }
+
+    // copy the `.tdata` contents page by page, utilizing the identity-mapping
+    let mut offset = 0;
+    while offset < template.file_size {
+        let src = VirtAddr::new(template.start_addr + offset);
+        let len = (template.file_size - offset).min(PAGE_SIZE - src.as_u64() % PAGE_SIZE);
+        let src_phys = kernel_page_table
+            .translate_addr(src)
+            .expect("TLS template is not mapped");
+        let data = unsafe { slice::from_raw_parts(src_phys.as_u64() as *const u8, len as usize) };
+        write_kernel_memory(kernel_page_table, start_addr + offset, data);
+        offset += len;
+    }
+    // initialize the self pointer of the thread control block
+    write_kernel_memory(
+        kernel_page_table,
+        thread_pointer,
+        &thread_pointer.as_u64().to_ne_bytes(),
+    );
+
+    TlsBlock {
+        start_addr: start_addr.as_u64(),
+        size,
+        thread_pointer: thread_pointer.as_u64(),
+    }
+}
+
+/// Writes the given bytes to the given address of the (inactive) kernel address space.
+///
+/// The target memory must be mapped already. It is written through the identity-mapping.
+fn write_kernel_memory(page_table: &OffsetPageTable, addr: VirtAddr, bytes: &[u8]) {
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let dst = addr + offset;
+        let len = (bytes.len() - offset).min((PAGE_SIZE - dst.as_u64() % PAGE_SIZE) as usize);
+        let dst_phys = page_table
+            .translate_addr(dst)
+            .expect("kernel memory is not mapped");
+        unsafe {
+            ptr::copy_nonoverlapping(bytes[offset..].as_ptr(), dst_phys.as_u64() as *mut u8, len)
+        };
+        offset += len;
+    }
+}
+
 /// Switches to the kernel address space and jumps to the kernel entry point.
 pub fn switch_to_kernel(
     page_tables: PageTables,
@@ -412,6 +835,11 @@ pub fn switch_to_kernel(
         boot_info,
     };
 
+    if let Some(tls_block) = mappings.tls_block {
+        // the FS base is not modified by the context switch, so we can already set it here
+        FsBase::write(VirtAddr::new(tls_block.thread_pointer));
+    }
+
     log::info!(
         "Jumping to kernel entry point at {:?}",
         addresses.entry_point
@@ -439,12 +867,14 @@ pub struct PageTables {
 /// Performs the actual context switch.
 unsafe fn context_switch(addresses: Addresses) -> ! {
     unsafe {
+        // also push the arguments onto the stack, which keeps the stack 16-byte aligned
         asm!(
-            "mov cr3, {}; mov rsp, {}; push 0; jmp {}",
+            "mov cr3, {}; mov rsp, {}; push rsi; push rdi; push 0; jmp {}",
             in(reg) addresses.page_table.start_address().as_u64(),
             in(reg) addresses.stack_top.as_u64(),
             in(reg) addresses.entry_point.as_u64(),
             in("rdi") addresses.boot_info as *const _ as usize,
+            in("rsi") BOOT_MAGIC,
         );
     }
     unreachable!();
@@ -472,13 +902,24 @@ fn frame_buffer_location(used_entries: &mut UsedLevel4Entries) -> VirtAddr {
         .unwrap_or_else(|| used_entries.get_free_address())
 }
 
-fn kernel_stack_start_location(used_entries: &mut UsedLevel4Entries) -> VirtAddr {
+fn modules_location(used_entries: &mut UsedLevel4Entries) -> VirtAddr {
     CONFIG
-        .kernel_stack_address
+        .modules_address
         .map(VirtAddr::new)
         .unwrap_or_else(|| used_entries.get_free_address())
 }
 
+/// Returns the start address of the kernel stack.
+///
+/// If no address is configured, the stack is placed behind a guard region of the given size at
+/// the start of a free level 4 entry.
+fn kernel_stack_start_location(used_entries: &mut UsedLevel4Entries, guard_size: u64) -> VirtAddr {
+    CONFIG
+        .kernel_stack_address
+        .map(VirtAddr::new)
+        .unwrap_or_else(|| used_entries.get_free_address() + guard_size)
+}
+
 fn enable_nxe_bit() {
     use x86_64::registers::control::{Efer, EferFlags};
     unsafe { Efer::update(|efer| *efer |= EferFlags::NO_EXECUTE_ENABLE) }
@@ -0,0 +1,440 @@
+use crate::{
+    binary::{
+        kernel_load_failed,
+        legacy_memory_region::{LegacyFrameAllocator, LegacyMemoryRegion},
+        parsed_config::CONFIG,
+        SystemInfo, PAGE_SIZE,
+    },
+    boot_info::{MemoryRegion, MemoryRegionKind, Module, PixelFormat},
+    kernel_check::LoadKernelError,
+    multiboot2::{info_tag, Multiboot2Kernel, BOOTLOADER_MAGIC},
+};
+use core::{arch::global_asm, mem::MaybeUninit, ptr, slice};
+use x86_64::{align_up, PhysAddr};
+
+/// The name that is reported in the bootloader name tag.
+const BOOTLOADER_NAME: &str = concat!("bootloader ", env!("CARGO_PKG_VERSION"));
+
+/// The boot information must be addressable from 32-bit protected mode.
+const MAX_BOOT_INFORMATION_ADDR: u64 = 0x1_0000_0000;
+
+/// Length of the RSDP structure of ACPI 1.0, which is extended by later versions.
+const RSDP_V1_LEN: usize = 20;
+
+/// Memory types of the Multiboot2 memory map.
+mod mmap_type {
+    pub const AVAILABLE: u32 = 1;
+    pub const RESERVED: u32 = 2;
+    pub const ACPI_RECLAIMABLE: u32 = 3;
+    pub const ACPI_NVS: u32 = 4;
+    pub const BAD: u32 = 5;
+}
+
+/// Looks for a Multiboot2 header in the given kernel file and parses the kernel.
+///
+/// Returns `None` if the kernel should be booted as native kernel. Panics if the kernel has a
+/// Multiboot2 header, but can't be booted.
+pub fn detect(kernel_bytes: &[u8]) -> Option<Multiboot2Kernel<'_>> {
+    Multiboot2Kernel::parse(kernel_bytes)
+        .map(|result| result.unwrap_or_else(|err| kernel_load_failed(err)))
+}
+
+/// Checks that the physical memory of the given Multiboot2 kernel is usable and does not
+/// overlap the given region, which is still in use by the bootloader.
+///
+/// Returns the end address of the kernel memory. The frame allocator must not return any
+/// frames below this address, since they might be overwritten by the kernel segments.
+pub fn check_kernel_memory<D: LegacyMemoryRegion>(
+    kernel: &Multiboot2Kernel,
+    memory_map: impl Iterator<Item = D> + Clone,
+    used_region: (PhysAddr, PhysAddr),
+) -> PhysAddr {
+    let (start, end) = kernel.load_range();
+    let (used_start, used_end) = (used_region.0.as_u64(), used_region.1.as_u64());
+    if start < used_end && used_start < end {
+        kernel_load_failed(LoadKernelError::PhysicalAddressInUse {
+            addr: start.max(used_start),
+        });
+    }
+    // the kernel memory might span multiple adjacent usable regions
+    let mut addr = start;
+    while addr < end {
+        let region = memory_map.clone().find(|region| {
+            region.kind() == MemoryRegionKind::Usable
+                && region.start().as_u64() <= addr
+                && addr < region.start().as_u64() + region.len()
+        });
+        match region {
+            Some(region) => addr = region.start().as_u64() + region.len(),
+            None => kernel_load_failed(LoadKernelError::PhysicalAddressInUse { addr }),
+        }
+    }
+    PhysAddr::new(end)
+}
+
+/// Loads the given Multiboot2 kernel to its physical addresses and switches to it.
+///
+/// The physical memory of the kernel must not be used by the bootloader or by the given frame
+/// allocator, see [`check_kernel_memory`]. The boot information is created from the given
+/// arguments, and the kernel is started in 32-bit protected mode with paging disabled, as
+/// required by the Multiboot2 specification.
+pub fn load_and_switch_to_kernel<I, D>(
+    kernel: &Multiboot2Kernel,
+    frame_allocator: LegacyFrameAllocator<I, D>,
+    system_info: SystemInfo,
+    modules: &[Module],
+) -> !
+where
+    I: ExactSizeIterator<Item = D> + Clone,
+    D: LegacyMemoryRegion,
+{
+    // the switch to protected mode disables paging, so the code must be identity-mapped and
+    // addressable from 32-bit mode
+    let trampoline_addr = multiboot2_enter as *const () as u64;
+    if trampoline_addr >= MAX_BOOT_INFORMATION_ADDR {
+        panic!(
+            "the bootloader must be located below 4GiB to boot Multiboot2 kernels, but it is \
+            located at {:#x}",
+            trampoline_addr
+        );
+    }
+
+    log::info!("Loading Multiboot2 kernel");
+    for segment in kernel.segments() {
+        let data = kernel.segment_data(&segment);
+        // the physical memory is identity-mapped
+        let dest = segment.phys_addr as *mut u8;
+        unsafe {
+            ptr::copy_nonoverlapping(data.as_ptr(), dest, data.len());
+            ptr::write_bytes(
+                dest.add(data.len()),
+                0,
+                (segment.mem_size - segment.file_size) as usize,
+            );
+        }
+    }
+
+    let boot_information = create_boot_information(frame_allocator, system_info, modules);
+
+    log::info!(
+        "Jumping to Multiboot2 kernel entry point at {:#x}",
+        kernel.entry_point()
+    );
+    unsafe { multiboot2_enter(kernel.entry_point(), boot_information) }
+}
+
+/// Creates the Multiboot2 boot information structure and returns its physical address.
+fn create_boot_information<I, D>(
+    mut frame_allocator: LegacyFrameAllocator<I, D>,
+    system_info: SystemInfo,
+    modules: &[Module],
+) -> u32
+where
+    I: ExactSizeIterator<Item = D> + Clone,
+    D: LegacyMemoryRegion,
+{
+    let rsdp = system_info.rsdp_addr.map(|addr| {
+        // the length of later versions is stored behind the ACPI 1.0 fields
+        let ptr = addr.as_u64() as *const u8;
+        let rsdp_v1 = unsafe { slice::from_raw_parts(ptr, RSDP_V1_LEN) };
+        let len = match rsdp_v1[15] {
+            0 => RSDP_V1_LEN,
+            _ => unsafe { ptr::read_unaligned(ptr.add(RSDP_V1_LEN) as *const u32) as usize },
+        };
+        unsafe { slice::from_raw_parts(ptr, len.max(RSDP_V1_LEN)) }
+    });
+    for module in modules {
+        if module.phys_addr + module.len as u64 > MAX_BOOT_INFORMATION_ADDR {
+            panic!(
+                "module `{}` is located above 4GiB, which is not addressable by Multiboot2 \
+                kernels (use the `max-phys-addr` key of the module entry)",
+                &*module.name
+            );
+        }
+    }
+
+    let memory_regions_len = frame_allocator.memory_map_len();
+    let tag_len = |payload_len: usize| align_up(8 + payload_len as u64, 8) as usize;
+    let len = 8
+        + tag_len(CONFIG.kernel_cmdline.len() + 1)
+        + tag_len(BOOTLOADER_NAME.len() + 1)
+        + modules
+            .iter()
+            .map(|module| tag_len(8 + module.name.len() + 1 + module.args.len() + 1))
+            .sum::<usize>()
+        + tag_len(8)
+        + tag_len(8 + memory_regions_len * 24)
+        + tag_len(30)
+        + rsdp.map_or(0, |rsdp| tag_len(RSDP_V1_LEN) + tag_len(rsdp.len()))
+        + tag_len(0);
+
+    let buffer = allocate_buffer(&mut frame_allocator, len, MAX_BOOT_INFORMATION_ADDR);
+    let regions = allocate_buffer(
+        &mut frame_allocator,
+        memory_regions_len * core::mem::size_of::<MemoryRegion>(),
+        u64::MAX,
+    );
+    let regions = unsafe {
+        slice::from_raw_parts_mut(
+            regions.as_mut_ptr() as *mut MaybeUninit<MemoryRegion>,
+            memory_regions_len,
+        )
+    };
+    let memory_regions = frame_allocator.construct_memory_map(regions);
+
+    let mut info = InfoWriter { buffer, len: 8 };
+
+    let tag = info.start_tag(info_tag::COMMAND_LINE);
+    info.write_str(CONFIG.kernel_cmdline);
+    info.end_tag(tag);
+
+    let tag = info.start_tag(info_tag::BOOTLOADER_NAME);
+    info.write_str(BOOTLOADER_NAME);
+    info.end_tag(tag);
+
+    // the module string starts with the module name, so that the kernel can identify modules
+    for module in modules {
+        let tag = info.start_tag(info_tag::MODULE);
+        info.write_u32(module.phys_addr as u32);
+        info.write_u32((module.phys_addr + module.len as u64) as u32);
+        info.write_bytes(module.name.as_bytes());
+        if !module.args.is_empty() {
+            info.write_bytes(b" ");
+        }
+        info.write_str(&module.args);
+        info.end_tag(tag);
+    }
+
+    let (lower, upper) = basic_memory_info(memory_regions);
+    let tag = info.start_tag(info_tag::BASIC_MEMORY_INFO);
+    info.write_u32((lower / 1024) as u32);
+    info.write_u32((upper / 1024) as u32);
+    info.end_tag(tag);
+
+    let tag = info.start_tag(info_tag::MEMORY_MAP);
+    info.write_u32(24); // entry size
+    info.write_u32(0); // entry version
+    for region in memory_regions.iter() {
+        info.write_u64(region.start);
+        info.write_u64(region.end - region.start);
+        info.write_u32(memory_type(region.kind));
+        info.write_u32(0);
+    }
+    info.end_tag(tag);
+
+    let framebuffer = system_info.framebuffer_info;
+    let tag = info.start_tag(info_tag::FRAMEBUFFER);
+    info.write_u64(system_info.framebuffer_addr.as_u64());
+    info.write_u32((framebuffer.stride * framebuffer.bytes_per_pixel) as u32);
+    info.write_u32(framebuffer.horizontal_resolution as u32);
+    info.write_u32(framebuffer.vertical_resolution as u32);
+    info.write_bytes(&[(framebuffer.bytes_per_pixel * 8) as u8, 1]); // direct RGB color
+    info.write_bytes(&[0, 0]);
+    // field position and mask size of red, green, and blue
+    let fields = match framebuffer.pixel_format {
+        PixelFormat::RGB => [0, 8, 8, 8, 16, 8],
+        PixelFormat::BGR => [16, 8, 8, 8, 0, 8],
+        // report grayscale as equal color channels
+        PixelFormat::U8 => [0, 8, 0, 8, 0, 8],
+    };
+    info.write_bytes(&fields);
+    info.end_tag(tag);
+
+    if let Some(rsdp) = rsdp {
+        let tag = info.start_tag(info_tag::ACPI_OLD_RSDP);
+        info.write_bytes(&rsdp[..RSDP_V1_LEN]);
+        info.end_tag(tag);
+        if rsdp.len() > RSDP_V1_LEN {
+            let tag = info.start_tag(info_tag::ACPI_NEW_RSDP);
+            info.write_bytes(rsdp);
+            info.end_tag(tag);
+        }
+    }
+
+    let tag = info.start_tag(info_tag::END);
+    info.end_tag(tag);
+
+    // header: total size and a reserved field
+    let len = info.len as u32;
+    info.buffer[..4].copy_from_slice(&len.to_le_bytes());
+
+    info.buffer.as_ptr() as u64 as u32
+}
+
+/// Allocates a zeroed buffer of physically contiguous frames that ends below `max_addr`.
+fn allocate_buffer<I, D>(
+    frame_allocator: &mut LegacyFrameAllocator<I, D>,
+    len: usize,
+    max_addr: u64,
+) -> &'static mut [u8]
+where
+    I: ExactSizeIterator<Item = D> + Clone,
+    D: LegacyMemoryRegion,
+{
+    let frames = align_up(len as u64, PAGE_SIZE) / PAGE_SIZE;
+    let start = frame_allocator
+        .allocate_frames(frames, PAGE_SIZE)
+        .expect("frame allocation for Multiboot2 boot information failed")
+        .start_address();
+    if start.as_u64() + len as u64 > max_addr {
+        panic!(
+            "Multiboot2 boot information must be located below 4GiB, but it was allocated at {:?}",
+            start
+        );
+    }
+    // the physical memory is identity-mapped
+    let buffer = unsafe { slice::from_raw_parts_mut(start.as_u64() as *mut u8, len) };
+    buffer.fill(0);
+    buffer
+}
+
+/// Returns the amount of lower memory (starting at address 0) and upper memory (starting at
+/// 1MiB) in bytes.
+///
+/// Only the contiguous usable memory at the start of both areas is counted. The lower memory
+/// is limited to 640KiB.
+fn basic_memory_info(regions: &[MemoryRegion]) -> (u64, u64) {
+    let contiguous_end = |start: u64| {
+        let mut end = start;
+        // the memory regions are not necessarily sorted
+        while let Some(region) = regions.iter().find(|region| {
+            memory_type(region.kind) == mmap_type::AVAILABLE
+                && region.start <= end
+                && end < region.end
+        }) {
+            end = region.end;
+        }
+        end
+    };
+    let lower = contiguous_end(0).min(0xa_0000);
+    let upper = contiguous_end(0x10_0000) - 0x10_0000;
+    (lower, upper)
+}
+
+/// Translates the kind of a memory region to the corresponding Multiboot2 memory type.
+///
+/// Following other Multiboot2 bootloaders, the memory of the modules and the boot information
+/// is reported as available. It is the responsibility of the kernel to not overwrite it while
+/// it is still needed.
+pub(super) fn memory_type(kind: MemoryRegionKind) -> u32 {
+    match kind {
+        MemoryRegionKind::Usable | MemoryRegionKind::Bootloader | MemoryRegionKind::Module => {
+            mmap_type::AVAILABLE
+        }
+        // the types of the E820 memory map are identical
+        MemoryRegionKind::UnknownBios(kind @ 1..=5) => kind,
+        MemoryRegionKind::UnknownUefi(kind) => match kind {
+            8 => mmap_type::BAD,              // EfiUnusableMemory
+            9 => mmap_type::ACPI_RECLAIMABLE, // EfiACPIReclaimMemory
+            10 => mmap_type::ACPI_NVS,        // EfiACPIMemoryNVS
+            _ => mmap_type::RESERVED,
+        },
+        _ => mmap_type::RESERVED,
+    }
+}
+
+/// Writes the tags of the Multiboot2 boot information to a zeroed buffer.
+struct InfoWriter {
+    buffer: &'static mut [u8],
+    len: usize,
+}
+
+impl InfoWriter {
+    /// Writes the header of a tag with the given type and returns its offset.
+    fn start_tag(&mut self, tag_type: u32) -> usize {
+        let start = self.len;
+        self.write_u32(tag_type);
+        self.write_u32(0); // size, set in `end_tag`
+        start
+    }
+
+    /// Sets the size of the tag at the given offset and pads it to 8 bytes.
+    fn end_tag(&mut self, start: usize) {
+        let size = (self.len - start) as u32;
+        self.buffer[start + 4..start + 8].copy_from_slice(&size.to_le_bytes());
+        self.len = align_up(self.len as u64, 8) as usize;
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        self.buffer[self.len..][..bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+    }
+
+    /// Writes the given string as null-terminated string.
+    fn write_str(&mut self, s: &str) {
+        self.write_bytes(s.as_bytes());
+        self.write_bytes(&[0]);
+    }
+
+    fn write_u32(&mut self, value: u32) {
+        self.write_bytes(&value.to_le_bytes());
+    }
+
+    fn write_u64(&mut self, value: u64) {
+        self.write_bytes(&value.to_le_bytes());
+    }
+}
+
+extern "sysv64" {
+    /// Switches from long mode to 32-bit protected mode and jumps to the given entry point.
+    ///
+    /// Loads a GDT with flat 32-bit code and data segments, disables paging and long mode, and
+    /// passes the Multiboot2 magic in `EAX` and the address of the boot information in `EBX`.
+    /// Must be located in identity-mapped memory below 4GiB.
+    fn multiboot2_enter(entry_point: u32, boot_information: u32) -> !;
+}
+
+global_asm!(
+    ".global multiboot2_enter",
+    "multiboot2_enter:",
+    "cli",
+    // paging can't be disabled while process-context identifiers are enabled
+    "mov rax, cr4",
+    "and rax, ~(1 << 17)",
+    "mov cr4, rax",
+    // load the GDT through a descriptor on the stack
+    "lea rax, [rip + multiboot2_gdt]",
+    "sub rsp, 16",
+    "mov [rsp + 2], rax",
+    "mov word ptr [rsp], 3 * 8 - 1",
+    "lgdt [rsp]",
+    // far return to the 32-bit code segment, which switches to compatibility mode
+    "push 0x08",
+    "lea rax, [rip + multiboot2_enter_32]",
+    "push rax",
+    "retfq",
+    ".code32",
+    "multiboot2_enter_32:",
+    "mov ax, 0x10",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov fs, ax",
+    "mov gs, ax",
+    "mov ss, ax",
+    // disable paging, which deactivates long mode
+    "mov eax, cr0",
+    "and eax, ~(1 << 31)",
+    "mov cr0, eax",
+    // clear the long mode enable bit in the EFER register
+    "mov ecx, 0xc0000080",
+    "rdmsr",
+    "and eax, ~(1 << 8)",
+    "wrmsr",
+    // disable physical address extension, which the kernel might not expect
+    "mov eax, cr4",
+    "and eax, ~(1 << 5)",
+    "mov cr4, eax",
+    "mov eax, {magic}",
+    "mov ebx, esi",
+    "jmp edi",
+    ".code64",
+    // the accessed bits are already set because the GDT is part of the bootloader code, which
+    // might be mapped read-only, so the CPU might not be able to set them when loading the
+    // segments
+    ".align 8",
+    "multiboot2_gdt:",
+    ".quad 0",
+    ".quad 0x00cf9b000000ffff", // 32-bit code segment
+    ".quad 0x00cf93000000ffff", // 32-bit data segment
+    magic = const BOOTLOADER_MAGIC,
+);
@@ -0,0 +1,44 @@
+use core::fmt;
+use x86_64::instructions::port::Port;
+
+/// A minimal writer for a 16550-compatible serial port.
+///
+/// The port is used as initialized by the firmware, so this type does not configure the baud
+/// rate or line settings. It is only intended for reporting fatal errors, e.g. when the kernel
+/// can't be loaded, so that they are visible in headless setups too.
+pub struct SerialPort {
+    base: u16,
+}
+
+impl SerialPort {
+    /// Returns a writer for the first serial port (`COM1`), which is used by QEMU's `-serial`
+    /// argument.
+    pub fn com1() -> Self {
+        SerialPort { base: 0x3f8 }
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        let mut data: Port<u8> = Port::new(self.base);
+        let mut line_status: Port<u8> = Port::new(self.base + 5);
+        // wait until the transmit holding register is empty, but don't hang if there is no
+        // serial port
+        for _ in 0..100_000 {
+            if unsafe { line_status.read() } & 0x20 != 0 {
+                break;
+            }
+        }
+        unsafe { data.write(byte) };
+    }
+}
+
+impl fmt::Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                self.write_byte(b'\r');
+            }
+            self.write_byte(byte);
+        }
+        Ok(())
+    }
+}
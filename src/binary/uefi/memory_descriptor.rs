@@ -1,4 +1,7 @@
-use crate::{binary::legacy_memory_region::LegacyMemoryRegion, boot_info::MemoryRegionKind};
+use crate::{
+    binary::{legacy_memory_region::LegacyMemoryRegion, uefi::MODULE_MEMORY_TYPE},
+    boot_info::MemoryRegionKind,
+};
 use uefi::table::boot::{MemoryDescriptor, MemoryType};
 use x86_64::PhysAddr;
 
@@ -16,6 +19,7 @@ impl<'a> LegacyMemoryRegion for MemoryDescriptor {
     fn kind(&self) -> MemoryRegionKind {
         match self.ty {
             MemoryType::CONVENTIONAL => MemoryRegionKind::Usable,
+            MODULE_MEMORY_TYPE => MemoryRegionKind::Module,
             other => MemoryRegionKind::UnknownUefi(other.0),
         }
     }
@@ -1 +1,10 @@
+use uefi::table::boot::MemoryType;
+
 mod memory_descriptor;
+
+/// The UEFI memory type that the bootloader uses for allocating module contents.
+///
+/// Memory types starting at `0x8000_0000` are reserved for use by UEFI OS loaders. Regions of
+/// this type are reported as [`MemoryRegionKind::Module`][crate::boot_info::MemoryRegionKind]
+/// in the boot info memory map.
+pub const MODULE_MEMORY_TYPE: MemoryType = MemoryType(0x8000_0000);
@@ -1,8 +1,18 @@
 use core::{
+    mem,
     ops::{self, Deref, DerefMut},
-    slice,
+    ptr, slice,
 };
 
+/// The magic value that the bootloader passes to the kernel entry point.
+///
+/// On entry, the `rdi` register contains the address of the [`BootInfo`] and the `rsi` register
+/// contains this value, which allows kernels to check that they were started by this bootloader.
+/// For kernels that expect their arguments on the stack, both values are also pushed onto the
+/// stack above the (zero) return address, i.e. the boot info address is at `[rsp + 8]` and the
+/// magic value is at `[rsp + 16]`.
+pub const BOOT_MAGIC: u64 = 0x424f_4f54_4c4f_4144;
+
 /// This structure represents the information that the bootloader passes to the kernel.
 ///
 /// The information is passed as an argument to the entry point. The entry point function must
@@ -64,6 +74,32 @@ pub struct BootInfo {
     pub tls_template: Optional<TlsTemplate>,
     /// Locations and names of all loaded modules
     pub modules: Modules,
+    /// The virtual base address at which the kernel was loaded.
+    ///
+    /// This offset was added to all virtual addresses of the kernel ELF file. It is only non-zero
+    /// for position-independent kernels, which are loaded at a dynamically chosen address. If the
+    /// `aslr` config option is enabled, this address is randomized on every boot.
+    pub kernel_base: u64,
+    /// The physical start address of the loaded kernel ELF file.
+    pub kernel_addr: u64,
+    /// The length of the loaded kernel ELF file in bytes.
+    pub kernel_len: u64,
+    /// The loadable (`PT_LOAD`) segments of the kernel, in the order of the ELF program headers.
+    pub kernel_segments: KernelSegments,
+    /// The virtual address of the kernel entry point.
+    pub kernel_entry: u64,
+    /// The symbol and string tables of the kernel executable.
+    ///
+    /// Only available if the `keep-symbols` config option is enabled and the kernel executable
+    /// contains a symbol table.
+    pub kernel_symbols: Optional<KernelSymbols>,
+    /// The thread local storage (TLS) block of the bootstrap CPU.
+    ///
+    /// Only available if the `map-tls-block` config option is enabled and the kernel executable
+    /// contains a TLS segment.
+    pub tls_block: Optional<TlsBlock>,
+    /// The location of the kernel stack.
+    pub kernel_stack: KernelStack,
 }
 
 /// FFI-safe slice of [`MemoryRegion`] structs, semantically equivalent to
@@ -144,6 +180,10 @@ pub enum MemoryRegionKind {
     ///
     /// This memory should _not_ be used by the kernel.
     Bootloader,
+    /// Memory containing the modules loaded by the bootloader.
+    ///
+    /// The kernel can reuse these frames after it no longer needs the module contents.
+    Module,
     /// An unknown memory region reported by the UEFI firmware.
     ///
     /// This should only be used if the UEFI memory type is known as usable.
@@ -248,6 +288,65 @@ pub struct TlsTemplate {
     ///
     /// Corresponds to the combined length of the `.tdata` and `.tbss` sections.
     pub mem_size: u64,
+    /// The required alignment of the thread local storage in memory.
+    pub align: u64,
+}
+
+/// A thread local storage (TLS) block that the bootloader set up for the bootstrap CPU.
+///
+/// The block uses the TLS layout of the x86_64 System V ABI (variant II): the initialized TLS
+/// data is located directly below the thread pointer, which points to a thread control block.
+/// The first word of the thread control block contains the thread pointer itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct TlsBlock {
+    /// The virtual start address of the block.
+    pub start_addr: u64,
+    /// The size of the block in bytes, including the thread control block.
+    pub size: u64,
+    /// The thread pointer, which was loaded into the `FS` base register.
+    pub thread_pointer: u64,
+}
+
+/// Describes the kernel stack that the bootloader set up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct KernelStack {
+    /// The lowest address of the stack.
+    ///
+    /// The stack grows downwards, so a stack overflow writes below this address.
+    pub bottom: u64,
+    /// The top address of the stack.
+    ///
+    /// The entry arguments and the return address are pushed at this address on entry to the
+    /// kernel, see [`BOOT_MAGIC`].
+    pub top: u64,
+    /// The size of the unmapped guard region directly below [`bottom`](Self::bottom).
+    pub guard_size: u64,
+    /// The pattern that the stack memory was filled with.
+    ///
+    /// Only available if the `kernel-stack-canary` config option is enabled.
+    pub canary: Optional<u64>,
+}
+
+impl KernelStack {
+    /// Returns the maximum number of bytes that were used on the stack so far.
+    ///
+    /// The usage is determined by searching the lowest stack word that no longer contains the
+    /// [`canary`](Self::canary) pattern. Returns `None` if the stack was not filled with a
+    /// canary pattern. If the stack overflowed its bottom, the full stack size is returned.
+    ///
+    /// ## Safety
+    ///
+    /// The stack must still be mapped at the reported addresses.
+    pub unsafe fn high_water_mark(&self) -> Option<u64> {
+        let canary = self.canary.into_option()?;
+        let mut addr = self.bottom;
+        while addr < self.top && unsafe { ptr::read_volatile(addr as *const u64) } == canary {
+            addr += mem::size_of::<u64>() as u64;
+        }
+        Some(self.top - addr)
+    }
 }
 
 /// FFI-safe variant of [`Option`].
@@ -351,35 +450,299 @@ impl DerefMut for Modules {
     }
 }
 
+impl Modules {
+    /// Returns the module with the given name, if any.
+    pub fn get(&self, name: &str) -> Option<&Module> {
+        self.iter().find(|module| module.name_str() == name)
+    }
+
+    /// Returns an iterator over the name and contents of all modules that are mapped into the
+    /// kernel's address space.
+    ///
+    /// ## Safety
+    ///
+    /// All modules with a non-zero `virt_addr` must fulfill the safety requirements of
+    /// [`Module::as_bytes`].
+    pub unsafe fn contents(&self) -> impl Iterator<Item = (&str, &[u8])> {
+        self.iter()
+            .filter(|module| module.virt_addr != 0)
+            .map(|module| (module.name_str(), unsafe { module.as_bytes() }))
+    }
+}
+
+/// An FFI-safe slice of [`KernelSegment`]s. Implements `Deref<Target = [KernelSegment]>`
+#[repr(C)]
+#[derive(Debug)]
+pub struct KernelSegments {
+    pub(crate) ptr: *mut KernelSegment,
+    pub(crate) len: usize,
+}
+
+impl From<&mut KernelSegments> for &mut [KernelSegment] {
+    fn from(v: &mut KernelSegments) -> Self {
+        unsafe { core::slice::from_raw_parts_mut(v.ptr, v.len) }
+    }
+}
+
+impl From<&KernelSegments> for &[KernelSegment] {
+    fn from(v: &KernelSegments) -> Self {
+        unsafe { core::slice::from_raw_parts(v.ptr, v.len) }
+    }
+}
+
+impl From<&'static mut [KernelSegment]> for KernelSegments {
+    fn from(v: &'static mut [KernelSegment]) -> Self {
+        KernelSegments {
+            ptr: v.as_mut_ptr(),
+            len: v.len(),
+        }
+    }
+}
+
+impl Deref for KernelSegments {
+    type Target = [KernelSegment];
+    fn deref(&self) -> &Self::Target {
+        self.into()
+    }
+}
+
+impl DerefMut for KernelSegments {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.into()
+    }
+}
+
+/// Describes a loadable segment of the kernel executable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct KernelSegment {
+    /// The virtual start address of the segment.
+    pub virt_addr: u64,
+    /// The size of the segment in memory, including zero-initialized `.bss` memory.
+    pub mem_size: u64,
+    /// The physical address that the start of the segment is mapped to.
+    ///
+    /// The segment is not necessarily physically contiguous. Most pages are mapped to the
+    /// corresponding frames of the ELF file, with a few exceptions: zero-initialized memory
+    /// and pages that needed to be modified (e.g. for relocations) are mapped to newly
+    /// allocated frames. If the `copy-kernel-segments` config option is enabled, all pages are
+    /// mapped to newly allocated frames. Use the page tables to translate other addresses of
+    /// the segment.
+    pub phys_addr: u64,
+    /// The size of the segment contents in the ELF file.
+    pub file_size: u64,
+    /// The ELF flags of the segment (`p_flags`).
+    ///
+    /// Use the [`is_executable`][Self::is_executable], [`is_writable`][Self::is_writable],
+    /// and [`is_readable`][Self::is_readable] methods to query them.
+    pub flags: u32,
+}
+
+impl KernelSegment {
+    /// Whether the segment is mapped as executable.
+    pub fn is_executable(&self) -> bool {
+        self.flags & 0x1 != 0
+    }
+
+    /// Whether the segment is mapped as writable.
+    pub fn is_writable(&self) -> bool {
+        self.flags & 0x2 != 0
+    }
+
+    /// Whether the segment is readable.
+    pub fn is_readable(&self) -> bool {
+        self.flags & 0x4 != 0
+    }
+}
+
+/// Describes the location of the kernel's ELF symbol table and its string table.
+///
+/// Both tables are mapped read-only into the kernel address space. They can be used to
+/// symbolize addresses, e.g. for printing backtraces in a panic handler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct KernelSymbols {
+    /// The virtual start address of the symbol table (the contents of the `.symtab` section).
+    ///
+    /// The table is an array of 24-byte `Elf64_Sym` entries.
+    pub symtab_addr: u64,
+    /// The length of the symbol table in bytes.
+    pub symtab_len: u64,
+    /// The virtual start address of the string table that contains the symbol names.
+    pub strtab_addr: u64,
+    /// The length of the string table in bytes.
+    pub strtab_len: u64,
+}
+
+impl KernelSymbols {
+    /// Returns the raw bytes of the symbol table.
+    ///
+    /// ## Safety
+    ///
+    /// The [`symtab_addr`](Self::symtab_addr) and [`symtab_len`](Self::symtab_len) fields must
+    /// describe mapped memory that is not modified while the returned slice is alive. This is
+    /// the case for the unmodified values reported by the bootloader, as long as the mapping is
+    /// kept.
+    pub unsafe fn symtab(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.symtab_addr as *const u8, self.symtab_len as usize) }
+    }
+
+    /// Returns the raw bytes of the string table.
+    ///
+    /// ## Safety
+    ///
+    /// The [`strtab_addr`](Self::strtab_addr) and [`strtab_len`](Self::strtab_len) fields must
+    /// describe mapped memory that is not modified while the returned slice is alive. This is
+    /// the case for the unmodified values reported by the bootloader, as long as the mapping is
+    /// kept.
+    pub unsafe fn strtab(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.strtab_addr as *const u8, self.strtab_len as usize) }
+    }
+
+    /// Looks up the symbol that contains the given address.
+    ///
+    /// Returns the (possibly mangled) symbol name and the offset of the address from the start
+    /// of the symbol. The symbol table contains link-time addresses, so for position-independent
+    /// kernels, the [`BootInfo::kernel_base`] must be subtracted from run-time addresses first.
+    ///
+    /// ## Safety
+    ///
+    /// Both tables must fulfill the safety requirements of [`symtab`](Self::symtab) and
+    /// [`strtab`](Self::strtab).
+    pub unsafe fn lookup(&self, addr: u64) -> Option<(&str, u64)> {
+        const SYMBOL_SIZE: usize = 24;
+
+        let read_u64 = |bytes: &[u8]| {
+            let mut buf = [0; 8];
+            buf.copy_from_slice(bytes);
+            u64::from_le_bytes(buf)
+        };
+        let symbol = unsafe { self.symtab() }
+            .chunks_exact(SYMBOL_SIZE)
+            .find(|symbol| {
+                let value = read_u64(&symbol[8..16]);
+                let size = read_u64(&symbol[16..24]);
+                addr >= value && addr < value.saturating_add(size)
+            })?;
+
+        let name_offset = u32::from_le_bytes([symbol[0], symbol[1], symbol[2], symbol[3]]);
+        let name = unsafe { self.strtab() }.get(name_offset as usize..)?;
+        let name_len = name.iter().position(|&b| b == 0)?;
+        let name = core::str::from_utf8(&name[..name_len]).ok()?;
+        Some((name, addr - read_u64(&symbol[8..16])))
+    }
+}
+
 /// Contains the name and pointer to a bootloader module.
 #[repr(C)]
 #[derive(Clone, Copy)]
 pub struct Module {
     /// Name of the module. This is the same name as specified in `package.metadata.bootloader.modules` in the kernel's `Cargo.toml`.
-    pub name: [u8; 32],
+    pub name: FfiStr,
     /// Physical address of the module in memory.
     pub phys_addr: u64,
+    /// Virtual address of the module in the kernel's address space.
+    ///
+    /// The module is mapped read-only and non-executable. The mapping location can be set
+    /// through the `modules-address` config option.
+    pub virt_addr: u64,
     /// Length of the module in bytes.
     pub len: usize,
+    /// Command line arguments for the module, as specified in the `args` key of the module
+    /// entry in `package.metadata.bootloader.modules`.
+    ///
+    /// Empty if no arguments were given.
+    pub args: FfiStr,
+    /// SHA-256 digest of the module contents.
+    ///
+    /// The digest is computed from the module file at build time. The bootloader refuses to
+    /// boot if the loaded module does not match it, so kernels can use it for logging or
+    /// attestation without hashing the module again.
+    pub sha256: [u8; 32],
+}
+
+impl Module {
+    /// Returns the name of the module as string slice.
+    ///
+    /// This is equivalent to dereferencing the [`name`](Self::name) field.
+    pub fn name_str(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the contents of the module as slice.
+    ///
+    /// This uses the virtual mapping created by the bootloader, so it only works in the
+    /// kernel's address space.
+    ///
+    /// ## Safety
+    ///
+    /// The [`virt_addr`](Self::virt_addr) and [`len`](Self::len) fields must describe mapped
+    /// memory that is not modified while the returned slice is alive. This is the case for
+    /// the unmodified modules reported by the bootloader, as long as their mapping is kept.
+    pub unsafe fn as_bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.virt_addr as *const u8, self.len) }
+    }
 }
 
 impl core::fmt::Debug for Module {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("Module")
-            .field(
-                "name",
-                &format_args!(
-                    r#"b"{}""#,
-                    core::str::from_utf8(&self.name)
-                        .unwrap()
-                        .trim_end_matches('\0')
-                ),
-            )
+            .field("name", &self.name)
             .field("phys_addr", &format_args!("{:#018X}", self.phys_addr))
+            .field("virt_addr", &format_args!("{:#018X}", self.virt_addr))
             .field("len", &self.len)
+            .field("args", &self.args)
+            .field("sha256", &Sha256Hex(&self.sha256))
             .finish()
     }
 }
 
+/// Formats a SHA-256 digest as lowercase hex string.
+struct Sha256Hex<'a>(&'a [u8; 32]);
+
+impl core::fmt::Debug for Sha256Hex<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for byte in self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// FFI-safe string slice, semantically equivalent to `&'static str`.
+///
+/// This type implements the [`Deref`][core::ops::Deref] trait, so it can be used like a `&str`.
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct FfiStr {
+    pub(crate) ptr: *const u8,
+    pub(crate) len: usize,
+}
+
+impl ops::Deref for FfiStr {
+    type Target = str;
+
+    fn deref(&self) -> &Self::Target {
+        // The fields are private, so they were either created from a `&'static str` or by the
+        // bootloader, which copies the strings into the boot info memory of the kernel.
+        unsafe { core::str::from_utf8_unchecked(slice::from_raw_parts(self.ptr, self.len)) }
+    }
+}
+
+impl From<&'static str> for FfiStr {
+    fn from(s: &'static str) -> Self {
+        FfiStr {
+            ptr: s.as_ptr(),
+            len: s.len(),
+        }
+    }
+}
+
+impl core::fmt::Debug for FfiStr {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::Debug::fmt(&**self, f)
+    }
+}
+
 /// Check that bootinfo is FFI-safe
 extern "C" fn _assert_ffi(_boot_info: BootInfo) {}
@@ -88,15 +88,149 @@ pub struct Config {
     /// fits them if 1 or more is set.
     pub minimum_framebuffer_width: Option<usize>,
     /// Modules to be linked to the image and loaded by the bootloader.
+    ///
+    /// Besides single `{ name = "…", path = "…" }` entries, the config also accepts
+    /// `{ dir = "firmware/", prefix = "fw/" }` entries, which add all files in the given
+    /// directory (recursively), and `{ glob = "drivers/*.elf", prefix = "…" }` entries, which add
+    /// all files that match the given pattern. The module names of these entries are the given
+    /// `prefix` followed by the file path relative to `dir` or to the kernel's `Cargo.toml`,
+    /// respectively. The files of each entry are sorted by path. The bootloader is rebuilt
+    /// whenever a file in the directory of a `dir` entry or in the leading wildcard-free
+    /// directory of a `glob` pattern changes, so glob patterns should start with a directory.
     pub modules: &'static [ModuleEntry],
+    /// Map the loaded modules at the specified virtual address.
+    ///
+    /// If not given, the bootloader searches for a free virtual memory region dynamically.
+    pub modules_address: Option<u64>,
+    /// Compress the kernel and module payloads with the given algorithm.
+    ///
+    /// The build script compresses the kernel executable and all module files, which makes
+    /// the disk images smaller and faster to load. The bootloader decompresses them into newly
+    /// allocated memory before loading the kernel. Currently, only `compression = "lz4"` is
+    /// supported.
+    ///
+    /// Defaults to `None`, i.e. no compression.
+    pub compression: Option<Compression>,
+    /// Whether to randomize the virtual address space layout of the kernel (KASLR).
+    ///
+    /// If enabled, the bootloader chooses a random unused level 4 entry for every mapping whose
+    /// address is not configured explicitly, e.g. for the kernel stack, the boot info, and the
+    /// physical memory mapping. Position-independent kernels are additionally loaded at a random
+    /// base address, which is reported in [`BootInfo::kernel_base`][crate::BootInfo::kernel_base].
+    /// The randomness is seeded from the `RDSEED` and `RDRAND` instructions, if supported by the
+    /// CPU, with the time stamp counter as fallback.
+    ///
+    /// Defaults to `false`.
+    pub aslr: bool,
+    /// Whether to copy kernel segments into 2MiB-aligned physical memory, so that they can be
+    /// mapped with 2MiB pages.
+    ///
+    /// The bootloader always maps the 2MiB-aligned chunks of kernel segments with 2MiB pages if
+    /// the corresponding segment data is also 2MiB-aligned in the loaded kernel file. If this
+    /// option is enabled, chunks whose data is not suitably aligned are copied into newly
+    /// allocated 2MiB frames instead of being mapped with 4KiB pages. This reduces the number of
+    /// page table entries and TLB misses for large kernels, at the cost of additional memory.
+    ///
+    /// Defaults to `false`.
+    pub kernel_huge_pages: bool,
+    /// Whether to copy the loadable kernel segments into newly allocated frames instead of
+    /// mapping the loaded kernel ELF file in place.
+    ///
+    /// If enabled, no kernel page aliases the memory of the loaded ELF file, so the bootloader
+    /// reports the memory of the ELF file as [`Usable`][crate::boot_info::MemoryRegionKind::Usable]
+    /// in the memory map. This requires additional memory while booting.
+    ///
+    /// Defaults to `false`.
+    pub copy_kernel_segments: bool,
+    /// Whether to pass the symbol table of the kernel executable to the kernel.
+    ///
+    /// If enabled, the bootloader copies the `.symtab` and `.strtab` sections of the kernel
+    /// executable into memory, maps them read-only, and reports them in
+    /// [`BootInfo::kernel_symbols`][crate::BootInfo::kernel_symbols]. This allows the kernel to
+    /// print symbolized backtraces, e.g. on panics. Note that only debug information is stripped
+    /// from the kernel at build time, so the symbol table is available unless the kernel is
+    /// stripped manually.
+    ///
+    /// Defaults to `false`.
+    pub keep_symbols: bool,
+    /// Whether to reject kernels with segments that are both writable and executable.
+    ///
+    /// If enabled, the bootloader refuses to boot a kernel that contains such a segment, since
+    /// mapping it would violate the W^X ("write xor execute") principle.
+    ///
+    /// Defaults to `false`.
+    pub strict_wx: bool,
+    /// Whether to set up thread local storage (TLS) for the bootstrap CPU.
+    ///
+    /// If enabled and the kernel executable contains a TLS segment, the bootloader allocates a
+    /// TLS block, initializes it from the TLS template of the kernel, and loads the `FS` base
+    /// register with the corresponding thread pointer before jumping to the kernel. This way,
+    /// thread locals can be used from the first instruction of the kernel. The block is
+    /// reported in [`BootInfo::tls_block`][crate::BootInfo::tls_block].
+    ///
+    /// Defaults to `false`.
+    pub map_tls_block: bool,
+    /// The size of the unmapped guard region below the kernel stack.
+    ///
+    /// A stack overflow into the guard region causes a page fault instead of silently
+    /// corrupting other memory. If `kernel_stack_address` is given, the guard region is located
+    /// directly below that address and must not be used by any other mapping. Otherwise, the
+    /// bootloader reserves it in front of the dynamically chosen stack location. The size must
+    /// be page-aligned. Setting it to zero disables the guard region.
+    ///
+    /// Defaults to one page (4KiB) if not given.
+    pub kernel_stack_guard_size: Option<u64>,
+    /// Whether to fill the kernel stack with a canary pattern before jumping to the kernel.
+    ///
+    /// The pattern is reported in [`BootInfo::kernel_stack`][crate::BootInfo::kernel_stack],
+    /// which allows the kernel to determine the maximum stack usage through
+    /// [`KernelStack::high_water_mark`][crate::boot_info::KernelStack::high_water_mark].
+    ///
+    /// Defaults to `false`.
+    pub kernel_stack_canary: bool,
+    /// The command line that is passed to the kernel.
+    ///
+    /// Only used for kernels that are booted using the Multiboot2 protocol, which receive it in
+    /// the command line tag of the boot information, and for Linux kernel images, which receive
+    /// it through the `cmd_line_ptr` field of the boot parameters.
+    ///
+    /// Defaults to an empty string.
+    pub kernel_cmdline: &'static str,
 }
 
 #[derive(Debug)]
 /// Describes a module to be used by the kernel.
 pub struct ModuleEntry {
-    /// Name the module will use at runtime. If the given name is not ASCII or does not
-    /// fit in 32 bytes, the builder will raise an error.
-    pub name: [u8; 32],
+    /// Name the module will use at runtime.
+    ///
+    /// The name may contain `/`-separated directory components (e.g. `drivers/net/e1000.ko`),
+    /// which are used as subdirectories of `efi/boot` on the UEFI boot partition.
+    pub name: &'static str,
     /// Path to the module file relative to the Cargo.toml file.
     pub path: &'static str,
+    /// Command line arguments that are passed to the kernel together with the module.
+    ///
+    /// Defaults to an empty string.
+    pub args: &'static str,
+    /// SHA-256 digest of the module file, computed at build time.
+    ///
+    /// The bootloader verifies the loaded module against this digest before starting the kernel.
+    pub sha256: [u8; 32],
+    /// Alignment of the module's physical start address in bytes, e.g. `0x200000` for 2 MiB.
+    ///
+    /// Must be a power of two. Modules are always at least page-aligned.
+    pub align: Option<u64>,
+    /// Place the module so that it ends at or below this physical address, e.g. `0x1_0000_0000`
+    /// for modules that must be below 4 GiB.
+    ///
+    /// Must be page-aligned. Defaults to no restriction.
+    pub max_phys_addr: Option<u64>,
+}
+
+/// The compression algorithms supported for the kernel and module payloads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// The [LZ4 block format](https://github.com/lz4/lz4/blob/dev/doc/lz4_Block_format.md),
+    /// prefixed with the uncompressed size as little-endian `u32`.
+    Lz4,
 }
@@ -1,6 +1,21 @@
-use std::{io, path::Path, process::Command};
+use crate::config::ModuleEntry;
+use std::{
+    fs::{self, File},
+    io::{self, Seek, Write},
+    path::Path,
+    process::Command,
+};
 use thiserror::Error;
 
+const BLOCK_SIZE: u64 = 512;
+
+/// The physical address at which the second stage of the BIOS bootloader places the kernel.
+const BIOS_KERNEL_ADDR: u64 = 0x40_0000;
+
+/// The second stage of the BIOS bootloader copies the kernel and modules through a 32-bit
+/// register, so they must end below this address.
+const BIOS_LOAD_LIMIT: u64 = 0x1_0000_0000;
+
 /// Creates a bootable disk image from the given bootloader executable.
 pub fn create_disk_image(
     bootloader_elf_path: &Path,
@@ -32,12 +47,126 @@ pub fn create_disk_image(
     Ok(())
 }
 
+/// Appends the given modules to a BIOS disk image created by [`create_disk_image`].
+///
+/// The modules are placed behind a module table, which the second stage of the BIOS
+/// bootloader loads into memory directly after the kernel. An empty module table is
+/// appended if `modules` is empty.
+///
+/// The `bootloader_elf_path` must point to the bootloader executable that the disk image was
+/// created from. It is used to determine the size of the embedded kernel, since the module
+/// table and the modules must fit between the end of the kernel and the 4 GiB boundary.
+pub fn append_bios_modules(
+    bootloader_elf_path: &Path,
+    output_bin_path: &Path,
+    modules: &[ModuleEntry],
+) -> Result<(), DiskImageError> {
+    // must be always identical with `binary::bios::module_table`
+    const MAGIC: &[u8; 8] = b"BLMODTAB";
+    const HEADER_SIZE: u64 = 16;
+    const ENTRY_SIZE: u64 = 16;
+    const MODULE_ALIGN: u64 = 4096;
+
+    fn align_up(value: u64, align: u64) -> u64 {
+        value.div_ceil(align) * align
+    }
+
+    let mut table = Vec::new();
+    let mut offset = align_up(
+        HEADER_SIZE + ENTRY_SIZE * modules.len() as u64,
+        MODULE_ALIGN,
+    );
+    let mut contents = Vec::new();
+    for module in modules {
+        let len = fs::metadata(module.path)
+            .map_err(|err| DiskImageError::Io {
+                message: "failed to read metadata of module file",
+                error: err,
+            })?
+            .len();
+        table.extend_from_slice(&offset.to_le_bytes());
+        table.extend_from_slice(&len.to_le_bytes());
+        contents.push((offset, module.path));
+        offset = align_up(offset + len, MODULE_ALIGN);
+    }
+
+    // the second stage loads the module table to the next page boundary behind the kernel
+    let kernel_size = embedded_kernel_size(bootloader_elf_path)?;
+    let table_addr = align_up(
+        BIOS_KERNEL_ADDR + align_up(kernel_size, BLOCK_SIZE),
+        MODULE_ALIGN,
+    );
+    let max = BIOS_LOAD_LIMIT.saturating_sub(table_addr);
+    if offset > max {
+        return Err(DiskImageError::ModulesTooLarge { size: offset, max });
+    }
+    let total_blocks = (offset / BLOCK_SIZE) as u32;
+
+    let mut image = fs::OpenOptions::new()
+        .write(true)
+        .open(output_bin_path)
+        .map_err(|err| DiskImageError::Io {
+            message: "failed to open boot image",
+            error: err,
+        })?;
+    let table_start = image
+        .seek(io::SeekFrom::End(0))
+        .map_err(|err| DiskImageError::Io {
+            message: "failed to seek to end of boot image",
+            error: err,
+        })?;
+
+    let mut header = Vec::new();
+    header.extend_from_slice(MAGIC);
+    header.extend_from_slice(&total_blocks.to_le_bytes());
+    header.extend_from_slice(&(modules.len() as u32).to_le_bytes());
+    image
+        .write_all(&header)
+        .and_then(|()| image.write_all(&table))
+        .map_err(|err| DiskImageError::Io {
+            message: "failed to write module table",
+            error: err,
+        })?;
+
+    for (offset, path) in contents {
+        image
+            .seek(io::SeekFrom::Start(table_start + offset))
+            .and_then(|_| File::open(path))
+            .and_then(|mut file| io::copy(&mut file, &mut image))
+            .map_err(|err| DiskImageError::Io {
+                message: "failed to copy module into boot image",
+                error: err,
+            })?;
+    }
+
+    image
+        .set_len(table_start + offset)
+        .map_err(|err| DiskImageError::Io {
+            message: "failed to pad modules to a multiple of the block size",
+            error: err,
+        })
+}
+
+/// Returns the size of the kernel that is embedded in the `.kernel` section of the given
+/// bootloader executable.
+fn embedded_kernel_size(bootloader_elf_path: &Path) -> Result<u64, DiskImageError> {
+    let bootloader = fs::read(bootloader_elf_path).map_err(|err| DiskImageError::Io {
+        message: "failed to read bootloader executable",
+        error: err,
+    })?;
+    let elf = xmas_elf::ElfFile::new(&bootloader).map_err(DiskImageError::InvalidBootloader)?;
+    elf.find_section_by_name(".kernel")
+        .map(|section| section.size())
+        .ok_or(DiskImageError::InvalidBootloader(
+            "the bootloader executable has no `.kernel` section",
+        ))
+}
+
 fn pad_to_nearest_block_size(output_bin_path: &Path) -> Result<(), DiskImageError> {
-    const BLOCK_SIZE: u64 = 512;
     use std::fs::OpenOptions;
     let file = OpenOptions::new()
         .write(true)
-        .open(&output_bin_path)
+        .open(output_bin_path)
         .map_err(|err| DiskImageError::Io {
             message: "failed to open boot image",
             error: err,
@@ -87,6 +216,22 @@ pub enum DiskImageError {
         stderr: Vec<u8>,
     },
 
+    /// The modules do not fit into the memory that the BIOS bootloader loads them to
+    #[error(
+        "The module table and modules take up {size} bytes, but only {max} bytes fit between \
+        the end of the kernel and the 4 GiB boundary"
+    )]
+    ModulesTooLarge {
+        /// The size of the module table and all modules in bytes
+        size: u64,
+        /// The maximum size in bytes
+        max: u64,
+    },
+
+    /// The size of the embedded kernel could not be read from the bootloader executable
+    #[error("Failed to parse the bootloader executable: {0}")]
+    InvalidBootloader(&'static str),
+
     /// An unexpected I/O error occurred
     #[error("I/O error: {message}:\n{error}")]
     Io {
@@ -0,0 +1,286 @@
+use core::fmt;
+use xmas_elf::{
+    header::{self, Machine},
+    program::{self, ProgramHeader, Type},
+    ElfFile,
+};
+
+const PAGE_SIZE: u64 = 4096;
+
+/// An error that occurred while validating or loading the kernel ELF file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum LoadKernelError {
+    /// The kernel is not a valid ELF file.
+    InvalidElf(&'static str),
+    /// The kernel was not compiled for the x86_64 architecture.
+    WrongMachine,
+    /// The kernel is neither an executable nor a position-independent executable.
+    WrongType,
+    /// A loadable segment contains a virtual address that is not canonical.
+    NonCanonicalAddress {
+        /// The virtual start address of the segment.
+        segment_addr: u64,
+    },
+    /// A loadable segment of a non-PIE kernel overlaps the first page of the virtual address
+    /// space.
+    ///
+    /// The zero page is never mapped because Rust assumes that no valid reference points to
+    /// address zero. The rest of the first level 4 entry is shared with the identity-mapped
+    /// bootloader, so collisions there are reported as [`AddressInUse`](Self::AddressInUse)
+    /// while loading.
+    ReservedAddress {
+        /// The virtual start address of the segment.
+        segment_addr: u64,
+    },
+    /// Two loadable segments overlap each other.
+    ///
+    /// Segments are mapped with page granularity, so segments that share a virtual page are
+    /// considered overlapping too. The segments of Multiboot2 kernels are copied to their
+    /// physical addresses instead, so only their actual physical memory must not overlap.
+    SegmentOverlap {
+        /// The start address of the first segment.
+        first_addr: u64,
+        /// The start address of the second segment.
+        second_addr: u64,
+    },
+    /// The virtual address and the file offset of a loadable segment are not congruent modulo
+    /// the page size, so the segment can't be mapped.
+    MisalignedSegment {
+        /// The virtual start address of the segment.
+        segment_addr: u64,
+        /// The offset of the segment in the ELF file.
+        offset: u64,
+    },
+    /// The entry point is not part of an executable segment.
+    EntryPointNotExecutable {
+        /// The virtual address of the entry point, or its physical address for Multiboot2
+        /// kernels.
+        entry_point: u64,
+    },
+    /// A loadable segment is both writable and executable, which is not allowed when the
+    /// `strict-wx` config option is enabled.
+    WritableAndExecutable {
+        /// The virtual start address of the segment.
+        segment_addr: u64,
+    },
+    /// The virtual memory of a segment is already in use by another mapping.
+    AddressInUse {
+        /// The virtual address that is already mapped.
+        addr: u64,
+    },
+    /// The Multiboot2 header of the kernel is malformed.
+    InvalidMultiboot2Header(&'static str),
+    /// The Multiboot2 header of the kernel contains a required tag or requests a boot
+    /// information tag that is not supported by the bootloader.
+    UnsupportedMultiboot2Tag {
+        /// The type of the unsupported tag.
+        tag_type: u32,
+    },
+    /// A segment of a Multiboot2 kernel is not located between 1MiB and 4GiB in physical
+    /// memory.
+    InvalidPhysicalAddress {
+        /// The physical start address of the segment.
+        segment_addr: u64,
+    },
+    /// The physical memory that a Multiboot2 kernel is loaded to is reserved or already in use.
+    PhysicalAddressInUse {
+        /// The physical address that is not available.
+        addr: u64,
+    },
+    /// The setup header of the Linux kernel image is malformed.
+    InvalidBzImage(&'static str),
+    /// The Linux kernel image can't be booted through its 64-bit entry point.
+    UnsupportedBzImage(&'static str),
+    /// The `kernel-cmdline` config option is longer than the command line buffer of the kernel.
+    KernelCmdlineTooLong {
+        /// The length of the command line in bytes.
+        len: usize,
+        /// The maximum length supported by the kernel.
+        max_len: usize,
+    },
+    /// Another error occurred while loading the kernel, e.g. while mapping its segments or
+    /// applying relocations.
+    Load(&'static str),
+}
+
+impl fmt::Display for LoadKernelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadKernelError::InvalidElf(err) => write!(f, "invalid kernel ELF file: {}", err),
+            LoadKernelError::WrongMachine => {
+                write!(f, "the kernel was not compiled for the x86_64 architecture")
+            }
+            LoadKernelError::WrongType => write!(
+                f,
+                "the kernel is neither an executable nor a position-independent executable"
+            ),
+            LoadKernelError::NonCanonicalAddress { segment_addr } => write!(
+                f,
+                "the kernel segment at {:#x} contains non-canonical addresses",
+                segment_addr
+            ),
+            LoadKernelError::ReservedAddress { segment_addr } => write!(
+                f,
+                "the kernel segment at {:#x} overlaps the reserved zero page",
+                segment_addr
+            ),
+            LoadKernelError::SegmentOverlap {
+                first_addr,
+                second_addr,
+            } => write!(
+                f,
+                "the kernel segments at {:#x} and {:#x} overlap",
+                first_addr, second_addr
+            ),
+            LoadKernelError::MisalignedSegment {
+                segment_addr,
+                offset,
+            } => write!(
+                f,
+                "the virtual address {:#x} and file offset {:#x} of a kernel segment are not \
+                congruent modulo the page size",
+                segment_addr, offset
+            ),
+            LoadKernelError::EntryPointNotExecutable { entry_point } => write!(
+                f,
+                "the kernel entry point {:#x} is not part of an executable segment",
+                entry_point
+            ),
+            LoadKernelError::WritableAndExecutable { segment_addr } => write!(
+                f,
+                "the kernel segment at {:#x} is both writable and executable, which is not \
+                allowed when `strict-wx` is enabled",
+                segment_addr
+            ),
+            LoadKernelError::AddressInUse { addr } => write!(
+                f,
+                "the kernel address {:#x} is already in use by another mapping",
+                addr
+            ),
+            LoadKernelError::InvalidMultiboot2Header(err) => {
+                write!(f, "invalid Multiboot2 header: {}", err)
+            }
+            LoadKernelError::UnsupportedMultiboot2Tag { tag_type } => write!(
+                f,
+                "the Multiboot2 header requires unsupported tag type {}",
+                tag_type
+            ),
+            LoadKernelError::InvalidPhysicalAddress { segment_addr } => write!(
+                f,
+                "the Multiboot2 kernel segment at {:#x} is not located between 1MiB and 4GiB",
+                segment_addr
+            ),
+            LoadKernelError::PhysicalAddressInUse { addr } => write!(
+                f,
+                "the physical address {:#x} of the Multiboot2 kernel is not available",
+                addr
+            ),
+            LoadKernelError::InvalidBzImage(err) => write!(f, "invalid bzImage: {}", err),
+            LoadKernelError::UnsupportedBzImage(err) => write!(f, "unsupported bzImage: {}", err),
+            LoadKernelError::KernelCmdlineTooLong { len, max_len } => write!(
+                f,
+                "the kernel command line is {} bytes long, but the kernel supports at most {} bytes",
+                len, max_len
+            ),
+            LoadKernelError::Load(err) => write!(f, "failed to load kernel: {}", err),
+        }
+    }
+}
+
+impl From<&'static str> for LoadKernelError {
+    fn from(err: &'static str) -> Self {
+        LoadKernelError::Load(err)
+    }
+}
+
+/// Checks that the given kernel ELF file can be loaded by the bootloader.
+///
+/// This function only inspects the ELF file, so it can be used both by the bootloader and by
+/// the builder, which checks the kernel before creating a disk image. If `strict_wx` is `true`,
+/// segments that are both writable and executable are rejected.
+pub fn check_kernel(elf_file: &ElfFile, strict_wx: bool) -> Result<(), LoadKernelError> {
+    header::sanity_check(elf_file).map_err(LoadKernelError::InvalidElf)?;
+    if !matches!(elf_file.header.pt2.machine().as_machine(), Machine::X86_64) {
+        return Err(LoadKernelError::WrongMachine);
+    }
+    let is_pie = match elf_file.header.pt2.type_().as_type() {
+        header::Type::Executable => false,
+        header::Type::SharedObject => true,
+        _ => return Err(LoadKernelError::WrongType),
+    };
+
+    let load_segments = || {
+        elf_file
+            .program_iter()
+            .filter(|segment| matches!(segment.get_type(), Ok(Type::Load)))
+    };
+    for segment in elf_file.program_iter() {
+        program::sanity_check(segment, elf_file).map_err(LoadKernelError::InvalidElf)?;
+    }
+    for (i, segment) in load_segments().enumerate() {
+        check_segment(segment, is_pie, strict_wx)?;
+        let (start, end) = page_range(segment);
+        for other in load_segments().skip(i + 1) {
+            let (other_start, other_end) = page_range(other);
+            if start < other_end && other_start < end {
+                return Err(LoadKernelError::SegmentOverlap {
+                    first_addr: segment.virtual_addr(),
+                    second_addr: other.virtual_addr(),
+                });
+            }
+        }
+    }
+
+    let entry_point = elf_file.header.pt2.entry_point();
+    let executable = load_segments().any(|segment| {
+        segment.flags().is_execute()
+            && entry_point >= segment.virtual_addr()
+            && entry_point < segment.virtual_addr() + segment.mem_size()
+    });
+    if !executable {
+        return Err(LoadKernelError::EntryPointNotExecutable { entry_point });
+    }
+
+    Ok(())
+}
+
+fn check_segment(
+    segment: ProgramHeader,
+    is_pie: bool,
+    strict_wx: bool,
+) -> Result<(), LoadKernelError> {
+    let segment_addr = segment.virtual_addr();
+    let end_addr = segment_addr
+        .checked_add(segment.mem_size())
+        .ok_or(LoadKernelError::NonCanonicalAddress { segment_addr })?;
+    // the segment must not cross the non-canonical hole
+    let lower_half = 0..=0x0000_8000_0000_0000;
+    let higher_half = 0xffff_8000_0000_0000..=u64::MAX;
+    let canonical = (lower_half.contains(&segment_addr) && lower_half.contains(&end_addr))
+        || (higher_half.contains(&segment_addr) && higher_half.contains(&end_addr));
+    if !canonical {
+        return Err(LoadKernelError::NonCanonicalAddress { segment_addr });
+    }
+    // the segments of PIE kernels are relative to a load address chosen by the bootloader
+    if !is_pie && segment_addr < PAGE_SIZE && segment.mem_size() > 0 {
+        return Err(LoadKernelError::ReservedAddress { segment_addr });
+    }
+    if segment_addr % PAGE_SIZE != segment.offset() % PAGE_SIZE {
+        return Err(LoadKernelError::MisalignedSegment {
+            segment_addr,
+            offset: segment.offset(),
+        });
+    }
+    if strict_wx && segment.flags().is_write() && segment.flags().is_execute() {
+        return Err(LoadKernelError::WritableAndExecutable { segment_addr });
+    }
+    Ok(())
+}
+
+/// Returns the page-aligned virtual address range of the given segment.
+fn page_range(segment: ProgramHeader) -> (u64, u64) {
+    let start = segment.virtual_addr() / PAGE_SIZE * PAGE_SIZE;
+    let end = segment.virtual_addr() + segment.mem_size();
+    (start, end.div_ceil(PAGE_SIZE) * PAGE_SIZE)
+}
@@ -63,6 +63,10 @@ This will result in the following files, which are placed in the specified `--ou
 The bootloader can be configured through a `[package.metadata.bootloader]` table in the
 `Cargo.toml` of the kernel (the one passed as `--kernel-manifest`). See the [`Config`] struct
 for all possible configuration options.
+
+Alternatively, the configuration can be embedded in the kernel executable through the [`config`]
+macro. This makes it possible to create disk images for prebuilt kernels without their
+`Cargo.toml`.
 */
 
 #![cfg_attr(not(feature = "builder"), no_std)]
@@ -73,7 +77,7 @@ for all possible configuration options.
 #![warn(missing_docs)]
 
 pub use crate::boot_info::BootInfo;
-pub use crate::config::{Config, ModuleEntry};
+pub use crate::config::{Compression, Config, ModuleEntry};
 
 /// Configuration options for the bootloader.
 mod config;
@@ -95,6 +99,27 @@ pub mod binary;
 #[cfg(feature = "builder")]
 pub mod disk_image;
 
+/// Checks whether a kernel ELF file can be loaded by the bootloader.
+///
+/// Used by both the bootloader and the `builder` binary. Only available when the `binary` or
+/// `builder` feature is enabled.
+#[cfg(any(feature = "binary", feature = "builder"))]
+pub mod kernel_check;
+
+/// Parses kernels that are booted using the Multiboot2 protocol.
+///
+/// Used by both the bootloader and the `builder` binary. Only available when the `binary` or
+/// `builder` feature is enabled.
+#[cfg(any(feature = "binary", feature = "builder"))]
+pub mod multiboot2;
+
+/// Parses Linux kernel images that are booted using the x86 boot protocol.
+///
+/// Used by both the bootloader and the `builder` binary. Only available when the `binary` or
+/// `builder` feature is enabled.
+#[cfg(any(feature = "binary", feature = "builder"))]
+pub mod linux;
+
 #[cfg(all(target_arch = "x86", not(feature = "builder")))]
 compile_error!(
     "This crate currently does not support 32-bit protected mode. \
@@ -115,15 +140,82 @@ compile_error!("This crate only supports the x86_64 architecture.");
 /// This macro just creates a function named `_start`, which the linker will use as the entry
 /// point. The advantage of using this macro instead of providing an own `_start` function is
 /// that the macro ensures that the function and argument types are correct.
+///
+/// By passing `verify_magic = true` as second argument, the generated function additionally
+/// checks that the kernel was started by this bootloader by comparing the boot magic value
+/// against [`BOOT_MAGIC`][boot_info::BOOT_MAGIC]. It panics if the values don't match:
+///
+/// ```ignore
+/// bootloader::entry_point!(kernel_main, verify_magic = true);
+/// ```
 #[macro_export]
 macro_rules! entry_point {
     ($path:path) => {
+        $crate::entry_point!($path, verify_magic = false);
+    };
+    ($path:path, verify_magic = $verify_magic:literal) => {
         #[export_name = "_start"]
-        pub extern "C" fn __impl_start(boot_info: &'static mut $crate::boot_info::BootInfo) -> ! {
+        pub extern "C" fn __impl_start(
+            boot_info: &'static mut $crate::boot_info::BootInfo,
+            magic: u64,
+        ) -> ! {
             // validate the signature of the program entry point
             let f: fn(&'static mut $crate::boot_info::BootInfo) -> ! = $path;
 
+            if $verify_magic && magic != $crate::boot_info::BOOT_MAGIC {
+                panic!(
+                    "kernel was not started by the bootloader (invalid boot magic {:#x})",
+                    magic
+                );
+            }
             f(boot_info)
         }
     };
 }
+
+/// Embeds the bootloader configuration in the kernel executable.
+///
+/// The argument must be a string constant that contains the same TOML keys as the
+/// `[package.metadata.bootloader]` table in the `Cargo.toml` of the kernel (see [`Config`]). The
+/// string is placed in the `.bootloader-config` section of the kernel executable, from which the
+/// bootloader build reads it. An embedded config takes precedence over the `Cargo.toml` of the
+/// kernel, so the `--kernel-manifest` argument of the `builder` is optional in this case:
+///
+/// ```ignore
+/// bootloader::config!(
+///     r#"
+///     map-physical-memory = true
+///     kernel-stack-size = 0x2_0000
+///     "#
+/// );
+/// ```
+///
+/// The config can also be loaded from a file, e.g. through
+/// `bootloader::config!(include_str!("../bootloader.toml"))`. Relative module paths are resolved
+/// against the directory of the `--kernel-manifest` if it is given and against the directory of
+/// the kernel executable otherwise.
+///
+/// The section is marked as `#[used]`, which makes it a retained section (`SHF_GNU_RETAIN`)
+/// that linkers like `rust-lld` keep despite `--gc-sections`, even though the kernel never
+/// references it. Kernels that use a linker without support for retained sections or a
+/// linker script with a catch-all `/DISCARD/` rule must keep the section explicitly through
+/// `KEEP(*(.bootloader-config))`.
+#[macro_export]
+macro_rules! config {
+    ($config:expr) => {
+        const __BOOTLOADER_CONFIG_STR: &str = $config;
+
+        #[used]
+        #[link_section = ".bootloader-config"]
+        static __BOOTLOADER_CONFIG: [u8; __BOOTLOADER_CONFIG_STR.len()] = {
+            let bytes = __BOOTLOADER_CONFIG_STR.as_bytes();
+            let mut config = [0; __BOOTLOADER_CONFIG_STR.len()];
+            let mut i = 0;
+            while i < bytes.len() {
+                config[i] = bytes[i];
+                i += 1;
+            }
+            config
+        };
+    };
+}
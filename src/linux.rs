@@ -0,0 +1,190 @@
+use crate::kernel_check::LoadKernelError;
+use core::convert::TryInto;
+
+/// Offset of the setup header in the kernel file and in the `boot_params` structure.
+pub const SETUP_HEADER_OFFSET: usize = 0x1f1;
+/// The magic value at offset `0x202` of the kernel file (`"HdrS"`).
+pub const HEADER_MAGIC: u32 = 0x5372_6448;
+/// The boot sector signature at offset `0x1fe` of the kernel file.
+const BOOT_FLAG: u16 = 0xaa55;
+/// The 64-bit entry point is only documented since boot protocol version 2.12.
+const MIN_VERSION: u16 = 0x020c;
+
+/// The number of setup sectors if the `setup_sects` field is zero.
+const DEFAULT_SETUP_SECTS: usize = 4;
+/// The size of a sector in the kernel file.
+const SECTOR_SIZE: usize = 512;
+/// Offset of the 64-bit entry point from the start of the protected-mode kernel.
+const ENTRY_64_OFFSET: u64 = 0x200;
+
+/// The protected-mode kernel must be loaded above 1MiB (`LOADED_HIGH` flag of `loadflags`).
+const LOADFLAGS_LOADED_HIGH: u8 = 1 << 0;
+/// The kernel has a 64-bit entry point (`XLF_KERNEL_64` flag of `xloadflags`).
+const XLOADFLAGS_KERNEL_64: u16 = 1 << 0;
+/// The kernel, the boot parameters, the command line, and the initrd may be located above 4GiB
+/// (`XLF_CAN_BE_LOADED_ABOVE_4G` flag of `xloadflags`).
+const XLOADFLAGS_CAN_BE_LOADED_ABOVE_4G: u16 = 1 << 1;
+
+/// Offsets of the fields of the setup header in the kernel file.
+mod field {
+    pub const SETUP_SECTS: usize = 0x1f1;
+    pub const BOOT_FLAG: usize = 0x1fe;
+    pub const JUMP: usize = 0x200;
+    pub const HEADER: usize = 0x202;
+    pub const VERSION: usize = 0x206;
+    pub const LOADFLAGS: usize = 0x211;
+    pub const INITRD_ADDR_MAX: usize = 0x22c;
+    pub const KERNEL_ALIGNMENT: usize = 0x230;
+    pub const RELOCATABLE_KERNEL: usize = 0x234;
+    pub const XLOADFLAGS: usize = 0x236;
+    pub const CMDLINE_SIZE: usize = 0x238;
+    pub const INIT_SIZE: usize = 0x260;
+}
+
+/// A Linux kernel image in the `bzImage` format, which is booted using the x86 boot protocol.
+///
+/// The image starts with the real-mode setup code, which contains the setup header. It is
+/// followed by the protected-mode kernel, which is copied to a suitably aligned physical address
+/// and entered through its 64-bit entry point. Only relocatable kernels with a 64-bit entry
+/// point are supported.
+pub struct BzImage<'a> {
+    bytes: &'a [u8],
+    setup_len: usize,
+    setup_header_end: usize,
+}
+
+impl<'a> BzImage<'a> {
+    /// Checks whether the given kernel file is a `bzImage` and parses its setup header.
+    ///
+    /// Returns `None` if the file has no setup header, i.e. if it is not a Linux kernel image.
+    /// Otherwise, the setup header is validated and kernels that can't be booted through the
+    /// 64-bit entry point are rejected.
+    pub fn parse(bytes: &'a [u8]) -> Option<Result<Self, LoadKernelError>> {
+        if read_u16(bytes, field::BOOT_FLAG)? != BOOT_FLAG
+            || read_u32(bytes, field::HEADER)? != HEADER_MAGIC
+        {
+            return None;
+        }
+        Some(Self::parse_header(bytes))
+    }
+
+    fn parse_header(bytes: &'a [u8]) -> Result<Self, LoadKernelError> {
+        let invalid = LoadKernelError::InvalidBzImage;
+        let unsupported = LoadKernelError::UnsupportedBzImage;
+
+        let setup_sects = match bytes[field::SETUP_SECTS] {
+            0 => DEFAULT_SETUP_SECTS,
+            sects => usize::from(sects),
+        };
+        let setup_len = (setup_sects + 1) * SECTOR_SIZE;
+        if setup_len + ENTRY_64_OFFSET as usize > bytes.len() {
+            return Err(invalid("the kernel file is shorter than its setup code"));
+        }
+        // the setup header ends at the target of the short jump at offset 0x200
+        let setup_header_end = field::HEADER + usize::from(bytes[field::JUMP + 1]);
+        if setup_header_end < field::INIT_SIZE + 4 || setup_header_end > setup_len {
+            return Err(invalid("the setup header has an invalid length"));
+        }
+
+        let kernel = Self {
+            bytes,
+            setup_len,
+            setup_header_end,
+        };
+        if kernel.read_u16(field::VERSION) < MIN_VERSION {
+            return Err(unsupported(
+                "boot protocol version 2.12 or later is required",
+            ));
+        }
+        if kernel.bytes[field::LOADFLAGS] & LOADFLAGS_LOADED_HIGH == 0 {
+            return Err(unsupported(
+                "the kernel is not loaded above 1MiB (zImage format)",
+            ));
+        }
+        if kernel.read_u16(field::XLOADFLAGS) & XLOADFLAGS_KERNEL_64 == 0 {
+            return Err(unsupported("the kernel has no 64-bit entry point"));
+        }
+        if kernel.bytes[field::RELOCATABLE_KERNEL] == 0 {
+            return Err(unsupported("the kernel is not relocatable"));
+        }
+        if !kernel.kernel_alignment().is_power_of_two() {
+            return Err(invalid("the kernel alignment is not a power of two"));
+        }
+        Ok(kernel)
+    }
+
+    /// Returns the setup header, starting at offset [`SETUP_HEADER_OFFSET`] of the kernel file.
+    ///
+    /// The setup header is copied to the same offset of the `boot_params` structure.
+    pub fn setup_header(&self) -> &'a [u8] {
+        &self.bytes[SETUP_HEADER_OFFSET..self.setup_header_end]
+    }
+
+    /// Returns the protected-mode kernel, which follows the setup code in the kernel file.
+    pub fn protected_mode_kernel(&self) -> &'a [u8] {
+        &self.bytes[self.setup_len..]
+    }
+
+    /// Returns the offset of the 64-bit entry point from the load address of the protected-mode
+    /// kernel.
+    pub fn entry_point_offset(&self) -> u64 {
+        ENTRY_64_OFFSET
+    }
+
+    /// Returns the amount of memory that the kernel needs at its load address, including the
+    /// memory for decompressing itself.
+    pub fn init_size(&self) -> u64 {
+        let init_size = u64::from(self.read_u32(field::INIT_SIZE));
+        init_size.max(self.protected_mode_kernel().len() as u64)
+    }
+
+    /// Returns the required alignment of the load address of the protected-mode kernel.
+    pub fn kernel_alignment(&self) -> u64 {
+        u64::from(self.read_u32(field::KERNEL_ALIGNMENT))
+    }
+
+    /// Returns the highest address that may be occupied by the initrd.
+    pub fn initrd_addr_max(&self) -> u64 {
+        if self.can_be_loaded_above_4g() {
+            u64::MAX
+        } else {
+            u64::from(self.read_u32(field::INITRD_ADDR_MAX))
+        }
+    }
+
+    /// Returns whether the kernel, the boot parameters, the command line, and the initrd may be
+    /// located above 4GiB.
+    pub fn can_be_loaded_above_4g(&self) -> bool {
+        self.read_u16(field::XLOADFLAGS) & XLOADFLAGS_CAN_BE_LOADED_ABOVE_4G != 0
+    }
+
+    /// Checks that the given command line fits into the command line buffer of the kernel.
+    pub fn check_cmdline(&self, cmdline: &str) -> Result<(), LoadKernelError> {
+        let max_len = self.read_u32(field::CMDLINE_SIZE) as usize;
+        if cmdline.len() > max_len {
+            return Err(LoadKernelError::KernelCmdlineTooLong {
+                len: cmdline.len(),
+                max_len,
+            });
+        }
+        Ok(())
+    }
+
+    fn read_u16(&self, offset: usize) -> u16 {
+        read_u16(self.bytes, offset).unwrap()
+    }
+
+    fn read_u32(&self, offset: usize) -> u32 {
+        read_u32(self.bytes, offset).unwrap()
+    }
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    let bytes = bytes.get(offset..offset.checked_add(2)?)?;
+    Some(u16::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    let bytes = bytes.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
@@ -0,0 +1,376 @@
+use crate::kernel_check::LoadKernelError;
+use core::convert::TryInto;
+use xmas_elf::{
+    header::{self, Machine},
+    program::{self, Type},
+    ElfFile,
+};
+
+/// The magic value at the start of the Multiboot2 header of a kernel.
+pub const HEADER_MAGIC: u32 = 0xe852_50d6;
+/// The magic value that is passed to Multiboot2 kernels in the `EAX` register.
+pub const BOOTLOADER_MAGIC: u32 = 0x36d7_6289;
+
+/// The Multiboot2 header must be contained in the first 32KiB of the kernel file.
+const HEADER_SEARCH_LEN: usize = 32768;
+/// The first MiB of physical memory is used by the BIOS and the BIOS bootloader.
+const LOAD_AREA_START: u64 = 0x10_0000;
+/// Multiboot2 kernels are started in 32-bit protected mode, so they must be loaded below 4GiB.
+const LOAD_AREA_END: u64 = 0x1_0000_0000;
+
+/// Types of the tags in the Multiboot2 boot information that the bootloader passes to the
+/// kernel.
+pub mod info_tag {
+    /// Terminates the list of tags.
+    pub const END: u32 = 0;
+    /// The command line of the kernel, as set through the `kernel-cmdline` config option.
+    pub const COMMAND_LINE: u32 = 1;
+    /// The name of the bootloader.
+    pub const BOOTLOADER_NAME: u32 = 2;
+    /// The location and command line of a module. Appears once per module.
+    pub const MODULE: u32 = 3;
+    /// The amount of lower and upper memory in KiB.
+    pub const BASIC_MEMORY_INFO: u32 = 4;
+    /// The physical memory map.
+    pub const MEMORY_MAP: u32 = 6;
+    /// The location and layout of the framebuffer.
+    pub const FRAMEBUFFER: u32 = 8;
+    /// A copy of the ACPI 1.0 RSDP structure.
+    pub const ACPI_OLD_RSDP: u32 = 14;
+    /// A copy of the ACPI 2.0 RSDP structure.
+    pub const ACPI_NEW_RSDP: u32 = 15;
+
+    /// The tags that the bootloader can provide, so that kernels may request them.
+    pub(crate) const SUPPORTED: &[u32] = &[
+        COMMAND_LINE,
+        BOOTLOADER_NAME,
+        MODULE,
+        BASIC_MEMORY_INFO,
+        MEMORY_MAP,
+        FRAMEBUFFER,
+        ACPI_OLD_RSDP,
+        ACPI_NEW_RSDP,
+    ];
+}
+
+/// Types of the tags in the Multiboot2 header of the kernel.
+mod header_tag {
+    pub const END: u16 = 0;
+    pub const INFORMATION_REQUEST: u16 = 1;
+    pub const ADDRESS: u16 = 2;
+    pub const ENTRY_ADDRESS: u16 = 3;
+    pub const CONSOLE_FLAGS: u16 = 4;
+    pub const FRAMEBUFFER: u16 = 5;
+    pub const MODULE_ALIGN: u16 = 6;
+    pub const RELOCATABLE: u16 = 10;
+}
+
+/// Marks a header tag as optional, i.e. the kernel can be booted without support for it.
+const TAG_FLAG_OPTIONAL: u16 = 1;
+
+/// A kernel that is booted using the Multiboot2 protocol.
+///
+/// Multiboot2 kernels are not mapped into a virtual address space. Instead, their segments are
+/// copied to fixed physical addresses and the kernel is started in 32-bit protected mode with
+/// paging disabled.
+pub struct Multiboot2Kernel<'a> {
+    bytes: &'a [u8],
+    elf_file: Option<ElfFile<'a>>,
+    raw_segment: Option<Multiboot2Segment>,
+    entry_point: u32,
+}
+
+/// A part of a Multiboot2 kernel that is loaded to a fixed physical address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Multiboot2Segment {
+    /// The physical address that the segment is loaded to.
+    pub phys_addr: u64,
+    /// The offset of the segment data in the kernel file.
+    pub file_offset: u64,
+    /// The number of bytes that are copied from the kernel file.
+    pub file_size: u64,
+    /// The size of the segment in memory. The memory behind the file data is zeroed.
+    pub mem_size: u64,
+}
+
+impl<'a> Multiboot2Kernel<'a> {
+    /// Looks for a Multiboot2 header in the given kernel file and parses the kernel.
+    ///
+    /// Returns `None` if the kernel contains no Multiboot2 header, i.e. if it should be booted
+    /// as native kernel. Otherwise, the header and the segments of the kernel are validated.
+    /// Kernels that request features that the bootloader doesn't support are rejected, unless
+    /// the corresponding header tags are marked as optional.
+    pub fn parse(bytes: &'a [u8]) -> Option<Result<Self, LoadKernelError>> {
+        let offset = find_header(bytes)?;
+        Some(Self::parse_at(bytes, offset))
+    }
+
+    fn parse_at(bytes: &'a [u8], offset: usize) -> Result<Self, LoadKernelError> {
+        let invalid = LoadKernelError::InvalidMultiboot2Header;
+        if read_u32(bytes, offset + 4) != Some(0) {
+            return Err(invalid("only the i386 architecture is supported"));
+        }
+        let header_len = read_u32(bytes, offset + 8).unwrap() as usize;
+        let header_end = offset
+            .checked_add(header_len)
+            .filter(|&end| end <= bytes.len().min(HEADER_SEARCH_LEN))
+            .ok_or(invalid("the header is not contained in the first 32KiB"))?;
+
+        let mut address_tag = None;
+        let mut entry_addr = None;
+        let mut tag_offset = offset + 16;
+        loop {
+            if tag_offset + 8 > header_end {
+                return Err(invalid("the header tags are not terminated by an end tag"));
+            }
+            let tag_type = read_u16(bytes, tag_offset).unwrap();
+            let flags = read_u16(bytes, tag_offset + 2).unwrap();
+            let size = read_u32(bytes, tag_offset + 4).unwrap() as usize;
+            if size < 8 || tag_offset + size > header_end {
+                return Err(invalid("a header tag has an invalid size"));
+            }
+            let optional = flags & TAG_FLAG_OPTIONAL != 0;
+            let field = |index: usize| {
+                Some(8 + 4 * index)
+                    .filter(|&field_offset| field_offset + 4 <= size)
+                    .and_then(|field_offset| read_u32(bytes, tag_offset + field_offset))
+                    .ok_or(invalid("a header tag is too short"))
+            };
+            match tag_type {
+                header_tag::END => break,
+                header_tag::INFORMATION_REQUEST if !optional => {
+                    for index in 0..(size - 8) / 4 {
+                        let tag_type = field(index)?;
+                        if !info_tag::SUPPORTED.contains(&tag_type) {
+                            return Err(LoadKernelError::UnsupportedMultiboot2Tag { tag_type });
+                        }
+                    }
+                }
+                header_tag::ADDRESS => {
+                    address_tag = Some((field(0)?, field(1)?, field(2)?, field(3)?));
+                }
+                header_tag::ENTRY_ADDRESS => entry_addr = Some(field(0)?),
+                // the video mode is never changed, but the framebuffer is always reported
+                header_tag::CONSOLE_FLAGS | header_tag::FRAMEBUFFER => {}
+                // modules are always page-aligned
+                header_tag::MODULE_ALIGN => {}
+                // the kernel is always loaded at its preferred address
+                header_tag::RELOCATABLE => {}
+                // other tags, e.g. requests to keep the UEFI boot services running, are ignored
+                // if they are optional
+                _ if optional => {}
+                other => {
+                    return Err(LoadKernelError::UnsupportedMultiboot2Tag {
+                        tag_type: other.into(),
+                    })
+                }
+            }
+            tag_offset += size.div_ceil(8) * 8;
+        }
+
+        let (elf_file, raw_segment) = match address_tag {
+            Some((header_addr, load_addr, load_end_addr, bss_end_addr)) => {
+                let segment = raw_segment(
+                    bytes,
+                    offset,
+                    header_addr,
+                    load_addr,
+                    load_end_addr,
+                    bss_end_addr,
+                )
+                .ok_or(invalid("the address tag does not match the kernel file"))?;
+                (None, Some(segment))
+            }
+            None => {
+                let elf_file = ElfFile::new(bytes).map_err(LoadKernelError::InvalidElf)?;
+                header::sanity_check(&elf_file).map_err(LoadKernelError::InvalidElf)?;
+                if !matches!(
+                    elf_file.header.pt2.machine().as_machine(),
+                    Machine::X86 | Machine::X86_64
+                ) {
+                    return Err(LoadKernelError::WrongMachine);
+                }
+                if !matches!(
+                    elf_file.header.pt2.type_().as_type(),
+                    header::Type::Executable
+                ) {
+                    return Err(LoadKernelError::WrongType);
+                }
+                for segment in elf_file.program_iter() {
+                    program::sanity_check(segment, &elf_file)
+                        .map_err(LoadKernelError::InvalidElf)?;
+                }
+                (Some(elf_file), None)
+            }
+        };
+
+        let mut kernel = Multiboot2Kernel {
+            bytes,
+            elf_file,
+            raw_segment,
+            entry_point: 0,
+        };
+        kernel.check_segments()?;
+
+        let entry_point = match (entry_addr, &kernel.elf_file) {
+            (Some(entry_addr), _) => u64::from(entry_addr),
+            (None, Some(elf_file)) => elf_entry_point(elf_file)?,
+            (None, None) => {
+                return Err(invalid("the address tag requires an entry address tag"));
+            }
+        };
+        if !kernel.segments().any(|segment| {
+            entry_point >= segment.phys_addr && entry_point < segment.phys_addr + segment.mem_size
+        }) {
+            return Err(LoadKernelError::EntryPointNotExecutable { entry_point });
+        }
+        // all segments are below 4GiB
+        kernel.entry_point = entry_point as u32;
+
+        Ok(kernel)
+    }
+
+    /// Returns the segments that are loaded to fixed physical addresses.
+    pub fn segments(&self) -> impl Iterator<Item = Multiboot2Segment> + '_ {
+        let elf_segments = self.elf_file.iter().flat_map(|elf_file| {
+            elf_file
+                .program_iter()
+                .filter(|segment| matches!(segment.get_type(), Ok(Type::Load)))
+                .map(|segment| Multiboot2Segment {
+                    phys_addr: segment.physical_addr(),
+                    file_offset: segment.offset(),
+                    file_size: segment.file_size(),
+                    mem_size: segment.mem_size(),
+                })
+        });
+        elf_segments.chain(self.raw_segment)
+    }
+
+    /// Returns the file data of the given segment.
+    pub fn segment_data(&self, segment: &Multiboot2Segment) -> &'a [u8] {
+        let start = segment.file_offset as usize;
+        &self.bytes[start..start + segment.file_size as usize]
+    }
+
+    /// Returns the physical address range `(start, end)` that spans all segments.
+    pub fn load_range(&self) -> (u64, u64) {
+        self.segments()
+            .filter(|segment| segment.mem_size > 0)
+            .map(|segment| (segment.phys_addr, segment.phys_addr + segment.mem_size))
+            .fold((u64::MAX, 0), |(start, end), (s, e)| {
+                (start.min(s), end.max(e))
+            })
+    }
+
+    /// Returns the physical address of the 32-bit entry point.
+    pub fn entry_point(&self) -> u32 {
+        self.entry_point
+    }
+
+    fn check_segments(&self) -> Result<(), LoadKernelError> {
+        let segments = || self.segments().filter(|segment| segment.mem_size > 0);
+        for (i, segment) in segments().enumerate() {
+            let segment_addr = segment.phys_addr;
+            let in_bounds = segment
+                .file_offset
+                .checked_add(segment.file_size)
+                .is_some_and(|end| end <= self.bytes.len() as u64);
+            if !in_bounds || segment.file_size > segment.mem_size {
+                return Err(LoadKernelError::InvalidElf(
+                    "segment data is not contained in the kernel file",
+                ));
+            }
+            let end_addr = segment_addr.checked_add(segment.mem_size);
+            if segment_addr < LOAD_AREA_START || end_addr.is_none_or(|end| end > LOAD_AREA_END) {
+                return Err(LoadKernelError::InvalidPhysicalAddress { segment_addr });
+            }
+            for other in segments().skip(i + 1) {
+                if segment_addr < other.phys_addr + other.mem_size
+                    && other.phys_addr < segment_addr + segment.mem_size
+                {
+                    return Err(LoadKernelError::SegmentOverlap {
+                        first_addr: segment_addr,
+                        second_addr: other.phys_addr,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns the offset of the first valid Multiboot2 header in the given kernel file.
+///
+/// The header must be 8-byte aligned and its checksum field must make the sum of the first four
+/// header fields zero.
+fn find_header(bytes: &[u8]) -> Option<usize> {
+    let search_len = bytes.len().min(HEADER_SEARCH_LEN);
+    (0..search_len).step_by(8).find(|&offset| {
+        let field = |index: usize| read_u32(bytes, offset + 4 * index);
+        match (field(0), field(1), field(2), field(3)) {
+            (Some(magic), Some(architecture), Some(header_len), Some(checksum)) => {
+                magic == HEADER_MAGIC
+                    && magic
+                        .wrapping_add(architecture)
+                        .wrapping_add(header_len)
+                        .wrapping_add(checksum)
+                        == 0
+            }
+            _ => false,
+        }
+    })
+}
+
+/// Creates the segment described by the address tag of the header at `header_offset`.
+///
+/// The address tag is used by kernels that are not ELF files. It specifies the physical
+/// address of the header, which determines the file offset that `load_addr` corresponds to.
+fn raw_segment(
+    bytes: &[u8],
+    header_offset: usize,
+    header_addr: u32,
+    load_addr: u32,
+    load_end_addr: u32,
+    bss_end_addr: u32,
+) -> Option<Multiboot2Segment> {
+    let file_offset =
+        (header_offset as u64).checked_sub(header_addr.checked_sub(load_addr)?.into())?;
+    let file_size = match load_end_addr {
+        0 => (bytes.len() as u64).checked_sub(file_offset)?,
+        end => u64::from(end.checked_sub(load_addr)?),
+    };
+    let mem_size = match bss_end_addr {
+        0 => file_size,
+        end => u64::from(end.checked_sub(load_addr)?),
+    };
+    Some(Multiboot2Segment {
+        phys_addr: load_addr.into(),
+        file_offset,
+        file_size,
+        mem_size,
+    })
+}
+
+/// Translates the virtual ELF entry point of the kernel to a physical address.
+fn elf_entry_point(elf_file: &ElfFile) -> Result<u64, LoadKernelError> {
+    let entry_point = elf_file.header.pt2.entry_point();
+    elf_file
+        .program_iter()
+        .filter(|segment| matches!(segment.get_type(), Ok(Type::Load)))
+        .find(|segment| {
+            entry_point >= segment.virtual_addr()
+                && entry_point < segment.virtual_addr() + segment.mem_size()
+        })
+        .map(|segment| entry_point - segment.virtual_addr() + segment.physical_addr())
+        .ok_or(LoadKernelError::EntryPointNotExecutable { entry_point })
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    let bytes = bytes.get(offset..offset.checked_add(2)?)?;
+    Some(u16::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    let bytes = bytes.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
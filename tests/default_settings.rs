@@ -15,6 +15,16 @@ fn check_boot_info() {
     run_test_binary("check_boot_info");
 }
 
+#[test]
+fn check_boot_magic() {
+    run_test_binary("check_boot_magic");
+}
+
+#[test]
+fn check_entry_arguments() {
+    run_test_binary("check_entry_arguments");
+}
+
 fn run_test_binary(bin_name: &str) {
     let mut cmd = Command::new(env!("CARGO"));
     cmd.current_dir("tests/test_kernels/default_settings");
@@ -20,6 +20,11 @@ fn verify_higher_half() {
     run_test_binary("verify_higher_half");
 }
 
+#[test]
+fn verify_kernel_stack() {
+    run_test_binary("verify_kernel_stack");
+}
+
 fn run_test_binary(bin_name: &str) {
     let mut cmd = Command::new(env!("CARGO"));
     cmd.current_dir("tests/test_kernels/higher_half");
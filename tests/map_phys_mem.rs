@@ -10,6 +10,16 @@ fn access_phys_mem() {
     run_test_binary("access_phys_mem");
 }
 
+#[test]
+fn verify_kernel_frames() {
+    run_test_binary("verify_kernel_frames");
+}
+
+#[test]
+fn verify_tls() {
+    run_test_binary("verify_tls");
+}
+
 fn run_test_binary(bin_name: &str) {
     let mut cmd = Command::new(env!("CARGO"));
     cmd.current_dir("tests/test_kernels/map_phys_mem");
@@ -0,0 +1,39 @@
+#![no_std] // don't link the Rust standard library
+#![no_main] // disable all Rust-level entry points
+
+use bootloader::{boot_info::MemoryRegionKind, entry_point, BootInfo};
+use core::panic::PanicInfo;
+use test_kernel_compression::{exit_qemu, QemuExitCode};
+
+entry_point!(kernel_main);
+
+fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
+    // the kernel was decompressed successfully, otherwise we wouldn't be here
+
+    // check that the module is passed decompressed
+    let modules = &boot_info.modules;
+    assert_eq!(modules.len(), 1);
+    let hello_world = &modules[0];
+    assert_eq!(&*hello_world.name, "hello_world");
+    assert_eq!(hello_world.len, 13);
+    assert_eq!(unsafe { hello_world.as_bytes() }, b"Hello, world!");
+
+    // the decompressed module is reported as module memory
+    let region = boot_info
+        .memory_regions
+        .iter()
+        .find(|r| r.start <= hello_world.phys_addr && hello_world.phys_addr < r.end)
+        .unwrap();
+    assert_eq!(region.kind, MemoryRegionKind::Module);
+
+    exit_qemu(QemuExitCode::Success);
+}
+
+/// This function is called on panic.
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    use core::fmt::Write;
+
+    let _ = writeln!(test_kernel_compression::serial(), "PANIC: {}", info);
+    exit_qemu(QemuExitCode::Failed);
+}
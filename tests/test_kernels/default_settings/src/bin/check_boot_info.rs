@@ -1,7 +1,10 @@
 #![no_std] // don't link the Rust standard library
 #![no_main] // disable all Rust-level entry points
 
-use bootloader::{boot_info::PixelFormat, entry_point, BootInfo};
+use bootloader::{
+    boot_info::{KernelSegment, PixelFormat},
+    entry_point, BootInfo,
+};
 use core::panic::PanicInfo;
 use test_kernel_default_settings::{exit_qemu, QemuExitCode};
 
@@ -55,6 +58,45 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
     // the test kernel has no TLS template
     assert_eq!(boot_info.tls_template.into_option(), None);
 
+    // the test kernel is not position-independent
+    assert_eq!(boot_info.kernel_base, 0);
+
+    // check kernel layout
+    assert!(boot_info.kernel_len > 0);
+    let contains = |segment: &&KernelSegment, addr: u64| {
+        addr >= segment.virt_addr && addr < segment.virt_addr + segment.mem_size
+    };
+    let entry_segment = boot_info
+        .kernel_segments
+        .iter()
+        .find(|s| contains(s, boot_info.kernel_entry))
+        .unwrap();
+    assert!(entry_segment.is_executable());
+    assert!(!entry_segment.is_writable());
+    let kernel_main_addr = kernel_main as *const () as u64;
+    assert!(boot_info
+        .kernel_segments
+        .iter()
+        .any(|s| contains(&s, kernel_main_addr)));
+    for segment in boot_info.kernel_segments.iter() {
+        assert_ne!(segment.phys_addr, 0);
+        assert_eq!(segment.phys_addr % 4096, segment.virt_addr % 4096);
+    }
+    // the unmodified code segment is mapped in place
+    assert!(entry_segment.phys_addr >= boot_info.kernel_addr);
+    assert!(
+        entry_segment.phys_addr + entry_segment.file_size
+            <= boot_info.kernel_addr + boot_info.kernel_len
+    );
+
+    // check kernel stack
+    let stack = boot_info.kernel_stack;
+    let stack_pointer = &stack as *const _ as u64;
+    assert!(stack.bottom <= stack_pointer && stack_pointer < stack.top);
+    assert_eq!(stack.guard_size, 4096);
+    assert_eq!(stack.canary.into_option(), None);
+    assert_eq!(unsafe { stack.high_water_mark() }, None);
+
     exit_qemu(QemuExitCode::Success);
 }
 
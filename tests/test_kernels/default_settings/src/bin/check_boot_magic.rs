@@ -0,0 +1,23 @@
+#![no_std] // don't link the Rust standard library
+#![no_main] // disable all Rust-level entry points
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use test_kernel_default_settings::{exit_qemu, QemuExitCode};
+
+// panics before calling `kernel_main` if the boot magic is invalid
+entry_point!(kernel_main, verify_magic = true);
+
+fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
+    assert!(boot_info.kernel_len > 0);
+    exit_qemu(QemuExitCode::Success);
+}
+
+/// This function is called on panic.
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    use core::fmt::Write;
+
+    let _ = writeln!(test_kernel_default_settings::serial(), "PANIC: {}", info);
+    exit_qemu(QemuExitCode::Failed);
+}
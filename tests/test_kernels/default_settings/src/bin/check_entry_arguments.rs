@@ -0,0 +1,44 @@
+#![no_std] // don't link the Rust standard library
+#![no_main] // disable all Rust-level entry points
+
+use bootloader::{boot_info::BOOT_MAGIC, BootInfo};
+use core::{arch::global_asm, panic::PanicInfo};
+use test_kernel_default_settings::{exit_qemu, QemuExitCode};
+
+// An entry point that reads its arguments from the stack, like kernels written in assembly.
+// It also checks that the arguments on the stack match the argument registers.
+global_asm!(
+    ".global _start",
+    "_start:",
+    "cmp qword ptr [rsp], 0",
+    "jne arguments_mismatch",
+    "cmp rdi, [rsp + 8]",
+    "jne arguments_mismatch",
+    "cmp rsi, [rsp + 16]",
+    "jne arguments_mismatch",
+    "mov rdi, [rsp + 8]",
+    "mov rsi, [rsp + 16]",
+    "jmp kernel_main",
+);
+
+#[no_mangle]
+extern "C" fn kernel_main(boot_info: &'static mut BootInfo, magic: u64) -> ! {
+    assert_eq!(magic, BOOT_MAGIC);
+    assert!(boot_info.kernel_len > 0);
+
+    exit_qemu(QemuExitCode::Success);
+}
+
+#[no_mangle]
+extern "C" fn arguments_mismatch() -> ! {
+    panic!("entry arguments on the stack don't match the argument registers");
+}
+
+/// This function is called on panic.
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    use core::fmt::Write;
+
+    let _ = writeln!(test_kernel_default_settings::serial(), "PANIC: {}", info);
+    exit_qemu(QemuExitCode::Failed);
+}
@@ -0,0 +1,34 @@
+#![no_std] // don't link the Rust standard library
+#![no_main] // disable all Rust-level entry points
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use test_kernel_embedded_config_only::{exit_qemu, serial, QemuExitCode};
+
+bootloader::config!(
+    r#"
+map-physical-memory = true
+physical-memory-offset = 0x0000_5000_0000_0000
+"#
+);
+
+entry_point!(kernel_main);
+
+fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
+    // the config section is kept by the linker even though the kernel never references it
+    assert_eq!(
+        boot_info.physical_memory_offset.into_option(),
+        Some(0x0000_5000_0000_0000),
+    );
+
+    exit_qemu(QemuExitCode::Success);
+}
+
+/// This function is called on panic.
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    use core::fmt::Write;
+
+    let _ = writeln!(serial(), "PANIC: {}", info);
+    exit_qemu(QemuExitCode::Failed);
+}
@@ -0,0 +1,33 @@
+#![no_std] // don't link the Rust standard library
+#![no_main] // disable all Rust-level entry points
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use test_kernel_higher_half::{exit_qemu, QemuExitCode};
+
+entry_point!(kernel_main);
+
+fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
+    let stack = boot_info.kernel_stack;
+    assert_eq!(stack.guard_size, 0x4000);
+    let stack_pointer = &stack as *const _ as u64;
+    assert!(stack.bottom <= stack_pointer && stack_pointer < stack.top);
+
+    // the unused part of the stack still contains the canary pattern
+    let canary = stack.canary.into_option().unwrap();
+    assert_eq!(unsafe { *(stack.bottom as *const u64) }, canary);
+    let used = unsafe { stack.high_water_mark() }.unwrap();
+    assert!(used >= stack.top - stack_pointer);
+    assert!(used < stack.top - stack.bottom);
+
+    exit_qemu(QemuExitCode::Success);
+}
+
+/// This function is called on panic.
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    use core::fmt::Write;
+
+    let _ = writeln!(test_kernel_higher_half::serial(), "PANIC: {}", info);
+    exit_qemu(QemuExitCode::Failed);
+}
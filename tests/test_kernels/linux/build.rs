@@ -0,0 +1,11 @@
+use std::{env, path::Path};
+
+fn main() {
+    // Linux kernel images are flat binaries that start with the setup header, so they need a
+    // custom linker script and no ELF output
+    let manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let linker_script = Path::new(&manifest_dir).join("linker.ld");
+    println!("cargo:rustc-link-arg=--script={}", linker_script.display());
+    println!("cargo:rustc-link-arg=--oformat=binary");
+    println!("cargo:rerun-if-changed={}", linker_script.display());
+}
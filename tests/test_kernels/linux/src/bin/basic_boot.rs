@@ -0,0 +1,27 @@
+#![no_std] // don't link the Rust standard library
+#![no_main] // disable all Rust-level entry points
+
+use core::{arch::global_asm, panic::PanicInfo};
+
+test_kernel_linux::bzimage_header!();
+
+// The boot parameters must start with a copy of the setup header.
+global_asm!(
+    ".global _start",
+    "_start:",
+    "cmp dword ptr [rsi + 0x202], 0x53726448",
+    "mov eax, 0x10",
+    "je 2f",
+    "mov eax, 0x11",
+    "2:",
+    "out 0xf4, eax",
+    "3:",
+    "hlt",
+    "jmp 3b",
+);
+
+/// This function is called on panic.
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    loop {}
+}
@@ -0,0 +1,87 @@
+#![no_std] // don't link the Rust standard library
+#![no_main] // disable all Rust-level entry points
+
+use core::{arch::global_asm, panic::PanicInfo};
+
+test_kernel_linux::bzimage_header!();
+
+// Checks the CPU state and the boot parameters that `rsi` points to.
+global_asm!(
+    ".global _start",
+    "_start:",
+    // the segment registers must be loaded with `__BOOT_CS` and `__BOOT_DS`
+    "mov ax, cs",
+    "cmp ax, 0x10",
+    "jne fail",
+    "mov ax, ds",
+    "cmp ax, 0x18",
+    "jne fail",
+    "mov ax, ss",
+    "cmp ax, 0x18",
+    "jne fail",
+    // the protected-mode kernel must be aligned to `kernel_alignment`
+    "lea rax, [rip + startup_64 - 0x200]",
+    "test rax, 0x1fffff",
+    "jnz fail",
+    // the setup header must be copied and the loader type must be set
+    "cmp dword ptr [rsi + 0x202], 0x53726448",
+    "jne fail",
+    "cmp byte ptr [rsi + 0x210], 0xff",
+    "jne fail",
+    // the command line must match the `kernel-cmdline` config option
+    "mov edx, [rsi + 0x228]",
+    "mov eax, [rsi + 0xc8]",
+    "shl rax, 32",
+    "or rdx, rax",
+    "lea rcx, [rip + expected_command_line]",
+    "2:",
+    "mov al, [rdx]",
+    "cmp al, [rcx]",
+    "jne fail",
+    "inc rdx",
+    "inc rcx",
+    "test al, al",
+    "jnz 2b",
+    // the memory map must not be empty and the first entry must start at address 0
+    "cmp byte ptr [rsi + 0x1e8], 0",
+    "je fail",
+    "cmp qword ptr [rsi + 0x2d0], 0",
+    "jne fail",
+    // the framebuffer must be reported as VESA linear framebuffer
+    "cmp byte ptr [rsi + 0x0f], 0x23",
+    "jne fail",
+    "cmp word ptr [rsi + 0x12], 0",
+    "je fail",
+    // the modules must be concatenated to the initrd and padded to 4 bytes
+    "cmp dword ptr [rsi + 0x21c], 12",
+    "jne fail",
+    "mov edx, [rsi + 0x218]",
+    "mov eax, [rsi + 0xc0]",
+    "shl rax, 32",
+    "or rdx, rax",
+    "cmp dword ptr [rdx], 0x73726966", // "firs"
+    "jne fail",
+    "cmp word ptr [rdx + 6], 0",
+    "jne fail",
+    "cmp dword ptr [rdx + 8], 0x0a6f7774", // "two\n"
+    "jne fail",
+    "mov eax, 0x10",
+    "jmp exit",
+    "fail:",
+    "mov eax, 0x11",
+    "exit:",
+    "out 0xf4, eax",
+    "3:",
+    "hlt",
+    "jmp 3b",
+    ".section .rodata",
+    "expected_command_line:",
+    ".asciz \"linux test\"",
+    ".text",
+);
+
+/// This function is called on panic.
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    loop {}
+}
@@ -0,0 +1,61 @@
+#![no_std]
+
+/// Defines the setup header of a relocatable `bzImage` with a 64-bit entry point, followed by the
+/// start of the protected-mode kernel.
+///
+/// The 64-bit entry point at offset `0x200` of the protected-mode kernel jumps to `_start`. The
+/// test kernels are position-independent, so they are written in assembly and only use
+/// RIP-relative addressing. They report their result through the `isa-debug-exit` device of
+/// QEMU: writing `0x10` to port `0xf4` signals success, writing `0x11` signals failure.
+#[macro_export]
+macro_rules! bzimage_header {
+    () => {
+        core::arch::global_asm!(
+            ".section .setup, \"a\"",
+            ".org 0x1f1",
+            ".byte 1",             // setup_sects
+            ".short 0",            // root_flags
+            ".long 0",             // syssize
+            ".short 0",            // ram_size
+            ".short 0xffff",       // vid_mode
+            ".short 0",            // root_dev
+            ".short 0xaa55",       // boot_flag
+            ".byte 0xeb, 2f - 1f", // jump over the setup header
+            "1:",
+            ".ascii \"HdrS\"",  // header
+            ".short 0x020f",    // version 2.15
+            ".long 0",          // realmode_swtch
+            ".short 0, 0",      // start_sys_seg, kernel_version
+            ".byte 0",          // type_of_loader
+            ".byte 1",          // loadflags: LOADED_HIGH
+            ".short 0",         // setup_move_size
+            ".long 0x100000",   // code32_start
+            ".long 0, 0, 0",    // ramdisk_image, ramdisk_size, bootsect_kludge
+            ".short 0",         // heap_end_ptr
+            ".byte 0, 0",       // ext_loader_ver, ext_loader_type
+            ".long 0",          // cmd_line_ptr
+            ".long 0x7fffffff", // initrd_addr_max
+            ".long 0x200000",   // kernel_alignment
+            ".byte 1",          // relocatable_kernel
+            ".byte 21",         // min_alignment
+            ".short 1",         // xloadflags: XLF_KERNEL_64
+            ".long 255",        // cmdline_size
+            ".long 0",          // hardware_subarch
+            ".quad 0",          // hardware_subarch_data
+            ".long 0, 0",       // payload_offset, payload_length
+            ".quad 0",          // setup_data
+            ".quad 0x1000000",  // pref_address
+            ".long 0x10000",    // init_size
+            ".long 0, 0",       // handover_offset, kernel_info_offset
+            "2:",
+            ".org 0x400",
+            // the 32-bit entry point at offset 0 is not supported
+            ".section .text.head, \"ax\"",
+            ".org 0x200, 0xf4",
+            ".global startup_64",
+            "startup_64:",
+            "jmp _start",
+            ".text",
+        );
+    };
+}
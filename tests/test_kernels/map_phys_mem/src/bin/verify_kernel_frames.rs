@@ -0,0 +1,63 @@
+#![no_std] // don't link the Rust standard library
+#![no_main] // disable all Rust-level entry points
+
+use bootloader::{boot_info::MemoryRegionKind, entry_point, BootInfo};
+use core::panic::PanicInfo;
+use test_kernel_map_phys_mem::{exit_qemu, serial, QemuExitCode};
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{OffsetPageTable, PageTable, Translate},
+    VirtAddr,
+};
+
+entry_point!(kernel_main);
+
+static mut DATA: [u8; 4] = [1, 2, 3, 4];
+
+fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    let page_table = unsafe {
+        let (frame, _) = Cr3::read();
+        let ptr: *mut PageTable = (phys_mem_offset + frame.start_address().as_u64()).as_mut_ptr();
+        OffsetPageTable::new(&mut *ptr, phys_mem_offset)
+    };
+
+    // the kernel segments are copied to new frames because of the `copy-kernel-segments`
+    // option, so they must not be part of a usable memory region
+    let code_addr = VirtAddr::new(kernel_main as *const () as u64);
+    let data_addr = VirtAddr::new(unsafe { core::ptr::addr_of!(DATA) } as u64);
+    for &addr in [code_addr, data_addr].iter() {
+        let phys_addr = page_table.translate_addr(addr).unwrap().as_u64();
+        let region = boot_info
+            .memory_regions
+            .iter()
+            .find(|r| r.start <= phys_addr && phys_addr < r.end)
+            .unwrap();
+        assert_eq!(region.kind, MemoryRegionKind::Bootloader);
+    }
+    assert_eq!(unsafe { DATA }, [1, 2, 3, 4]);
+
+    // the reported segment addresses point to the copied frames
+    for segment in boot_info.kernel_segments.iter() {
+        let phys_addr = page_table
+            .translate_addr(VirtAddr::new(segment.virt_addr))
+            .unwrap()
+            .as_u64();
+        assert_eq!(segment.phys_addr, phys_addr);
+        assert!(
+            phys_addr < boot_info.kernel_addr
+                || phys_addr >= boot_info.kernel_addr + boot_info.kernel_len
+        );
+    }
+
+    exit_qemu(QemuExitCode::Success);
+}
+
+/// This function is called on panic.
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    use core::fmt::Write;
+
+    let _ = writeln!(serial(), "PANIC: {}", info);
+    exit_qemu(QemuExitCode::Failed);
+}
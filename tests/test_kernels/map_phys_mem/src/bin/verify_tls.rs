@@ -0,0 +1,51 @@
+#![no_std] // don't link the Rust standard library
+#![no_main] // disable all Rust-level entry points
+#![feature(thread_local)]
+
+use bootloader::{entry_point, BootInfo};
+use core::{cell::Cell, panic::PanicInfo};
+use test_kernel_map_phys_mem::{exit_qemu, serial, QemuExitCode};
+use x86_64::registers::model_specific::FsBase;
+
+entry_point!(kernel_main);
+
+#[thread_local]
+static DATA: Cell<u64> = Cell::new(0xdead_beef);
+
+#[thread_local]
+static BSS: Cell<u64> = Cell::new(0);
+
+fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
+    let template = boot_info.tls_template.into_option().unwrap();
+    let tls_block = boot_info.tls_block.into_option().unwrap();
+
+    // the FS base points to the thread control block, which starts with a self pointer
+    let thread_pointer = FsBase::read().as_u64();
+    assert_eq!(thread_pointer, tls_block.thread_pointer);
+    assert_eq!(unsafe { *(thread_pointer as *const u64) }, thread_pointer);
+    assert!(tls_block.start_addr + template.mem_size <= thread_pointer);
+    assert!(thread_pointer + 8 <= tls_block.start_addr + tls_block.size);
+
+    // `.tdata` is initialized from the template and `.tbss` is zeroed
+    assert_eq!(DATA.get(), 0xdead_beef);
+    assert_eq!(BSS.get(), 0);
+    DATA.set(1);
+    BSS.set(2);
+    assert_eq!(DATA.get(), 1);
+    assert_eq!(BSS.get(), 2);
+
+    // the template itself is not modified
+    let template_data = unsafe { *(template.start_addr as *const u64) };
+    assert_eq!(template_data, 0xdead_beef);
+
+    exit_qemu(QemuExitCode::Success);
+}
+
+/// This function is called on panic.
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    use core::fmt::Write;
+
+    let _ = writeln!(serial(), "PANIC: {}", info);
+    exit_qemu(QemuExitCode::Failed);
+}
@@ -1,7 +1,10 @@
 #![no_std] // don't link the Rust standard library
 #![no_main] // disable all Rust-level entry points
 
-use bootloader::{boot_info::PixelFormat, entry_point, BootInfo};
+use bootloader::{
+    boot_info::{MemoryRegionKind, PixelFormat},
+    entry_point, BootInfo,
+};
 use core::panic::PanicInfo;
 use test_kernel_modules::{exit_qemu, QemuExitCode};
 
@@ -55,13 +58,55 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
     // the test kernel has no TLS template
     assert_eq!(boot_info.tls_template.into_option(), None);
 
-    let modules = &mut *boot_info.modules;
-    assert_eq!(modules.len(), 2);
-    assert!(modules.iter().find(|module| module.name == "foo").is_some());
-    assert!(modules
+    let modules = &boot_info.modules;
+    assert_eq!(modules.len(), 5);
+    let foo = modules.get("foo").unwrap();
+    assert_eq!(&*foo.args, "root=/dev/ram0 quiet");
+    // foo is 2 MiB aligned and below 4 GiB
+    assert_eq!(foo.phys_addr % 0x20_0000, 0);
+    assert!(foo.phys_addr + foo.len as u64 <= 0x1_0000_0000);
+    let hello_world = modules.get("hello_world").unwrap();
+    assert_eq!(unsafe { hello_world.as_bytes() }, b"Hello, world!");
+    assert_eq!(&*hello_world.args, "");
+    // SHA-256 digest of `modules/hello_world.txt`
+    assert_eq!(
+        hello_world.sha256,
+        [
+            0x31, 0x5f, 0x5b, 0xdb, 0x76, 0xd0, 0x78, 0xc4, 0x3b, 0x8a, 0xc0, 0x06, 0x4e, 0x4a,
+            0x01, 0x64, 0x61, 0x2b, 0x1f, 0xce, 0x77, 0xc8, 0x69, 0x34, 0x5b, 0xfc, 0x94, 0xc7,
+            0x58, 0x94, 0xed, 0xd3
+        ]
+    );
+
+    let nested = modules
+        .get("drivers/net/a_module_name_longer_than_32_bytes.ko")
+        .unwrap();
+    assert_eq!(unsafe { nested.as_bytes() }, b"bar");
+
+    // directory entries are expanded in sorted order
+    assert_eq!(&*modules[3].name, "fw/a.bin");
+    assert_eq!(unsafe { modules[3].as_bytes() }, b"first");
+    assert_eq!(&*modules[4].name, "fw/nested/b.bin");
+    assert_eq!(unsafe { modules[4].as_bytes() }, b"second");
+
+    // all modules are mapped
+    let mut contents = unsafe { modules.contents() };
+    assert_eq!(contents.next(), Some(("foo", unsafe { foo.as_bytes() })));
+    assert_eq!(contents.nth(3), Some(("fw/nested/b.bin", &b"second"[..])));
+    assert_eq!(contents.next(), None);
+    assert_eq!(
+        modules.get("fw/nested/b.bin").unwrap().name_str(),
+        "fw/nested/b.bin"
+    );
+    assert!(modules.get("missing").is_none());
+
+    // the module contents are reported as module memory
+    let region = boot_info
+        .memory_regions
         .iter()
-        .find(|module| module.name == "hello_world")
-        .is_some());
+        .find(|r| r.start <= hello_world.phys_addr && hello_world.phys_addr < r.end)
+        .unwrap();
+    assert_eq!(region.kind, MemoryRegionKind::Module);
 
     exit_qemu(QemuExitCode::Success);
 }
@@ -0,0 +1,9 @@
+use std::{env, path::Path};
+
+fn main() {
+    // Multiboot2 kernels are loaded to physical addresses, so they need a custom linker script
+    let manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    let linker_script = Path::new(&manifest_dir).join("linker.ld");
+    println!("cargo:rustc-link-arg=--script={}", linker_script.display());
+    println!("cargo:rerun-if-changed={}", linker_script.display());
+}
@@ -0,0 +1,28 @@
+#![no_std] // don't link the Rust standard library
+#![no_main] // disable all Rust-level entry points
+
+use core::{arch::global_asm, panic::PanicInfo};
+
+test_kernel_multiboot2::multiboot2_header!();
+
+global_asm!(
+    ".code32",
+    ".global _start",
+    "_start:",
+    "cmp eax, 0x36d76289",
+    "mov eax, 0x10",
+    "je 2f",
+    "mov eax, 0x11",
+    "2:",
+    "out 0xf4, eax",
+    "3:",
+    "hlt",
+    "jmp 3b",
+    ".code64",
+);
+
+/// This function is called on panic.
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    loop {}
+}
@@ -0,0 +1,102 @@
+#![no_std] // don't link the Rust standard library
+#![no_main] // disable all Rust-level entry points
+
+use core::{arch::global_asm, panic::PanicInfo};
+
+test_kernel_multiboot2::multiboot2_header!();
+
+// Walks the tags of the boot information and records the found tag types as bits in `edi`.
+// The stack pointer is undefined on entry, so the stack is not used.
+global_asm!(
+    ".code32",
+    ".global _start",
+    "_start:",
+    "cmp eax, 0x36d76289",
+    "jne fail",
+    // the boot information must be 8-byte aligned
+    "test ebx, 7",
+    "jnz fail",
+    "lea esi, [ebx + 8]",
+    "xor edi, edi",
+    "next_tag:",
+    "mov eax, [esi]",
+    "mov ecx, [esi + 4]",
+    "cmp eax, 0",
+    "je done",
+    "cmp eax, 1",
+    "je command_line",
+    "cmp eax, 4",
+    "je basic_memory_info",
+    "cmp eax, 6",
+    "je memory_map",
+    "cmp eax, 8",
+    "je framebuffer",
+    "cmp eax, 31",
+    "ja tag_done",
+    "bts edi, eax",
+    "jmp tag_done",
+    // the command line must match the `kernel-cmdline` config option
+    "command_line:",
+    "lea edx, [esi + 8]",
+    "mov ebp, offset expected_command_line",
+    "command_line_loop:",
+    "mov al, [edx]",
+    "cmp al, [ebp]",
+    "jne fail",
+    "inc edx",
+    "inc ebp",
+    "test al, al",
+    "jnz command_line_loop",
+    "bts edi, 1",
+    "jmp tag_done",
+    // there must be upper memory
+    "basic_memory_info:",
+    "cmp dword ptr [esi + 12], 0",
+    "je fail",
+    "bts edi, 4",
+    "jmp tag_done",
+    // the entry size must be 24 and the first entry must start at address 0
+    "memory_map:",
+    "cmp dword ptr [esi + 8], 24",
+    "jne fail",
+    "cmp dword ptr [esi + 16], 0",
+    "jne fail",
+    "bts edi, 6",
+    "jmp tag_done",
+    // the framebuffer must use direct RGB colors
+    "framebuffer:",
+    "cmp byte ptr [esi + 29], 1",
+    "jne fail",
+    "bts edi, 8",
+    // tags are padded to 8 bytes
+    "tag_done:",
+    "add ecx, 7",
+    "and ecx, ~7",
+    "add esi, ecx",
+    "jmp next_tag",
+    "done:",
+    // command line, bootloader name, basic memory info, memory map, and framebuffer
+    "and edi, (1 << 1) | (1 << 2) | (1 << 4) | (1 << 6) | (1 << 8)",
+    "cmp edi, (1 << 1) | (1 << 2) | (1 << 4) | (1 << 6) | (1 << 8)",
+    "jne fail",
+    "mov eax, 0x10",
+    "out 0xf4, eax",
+    "jmp halt",
+    "fail:",
+    "mov eax, 0x11",
+    "out 0xf4, eax",
+    "halt:",
+    "hlt",
+    "jmp halt",
+    ".code64",
+    ".section .rodata",
+    "expected_command_line:",
+    ".asciz \"multiboot2 test\"",
+    ".text",
+);
+
+/// This function is called on panic.
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    loop {}
+}
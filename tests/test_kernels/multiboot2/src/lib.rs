@@ -0,0 +1,32 @@
+#![no_std]
+
+/// Defines a Multiboot2 header that requests the command line, memory map, and framebuffer
+/// tags of the boot information.
+///
+/// The test kernels are entered in 32-bit protected mode, so they are written in assembly. They
+/// report their result through the `isa-debug-exit` device of QEMU: writing `0x10` to port
+/// `0xf4` signals success, writing `0x11` signals failure.
+#[macro_export]
+macro_rules! multiboot2_header {
+    () => {
+        core::arch::global_asm!(
+            ".section .multiboot2, \"a\"",
+            ".align 8",
+            "multiboot2_header:",
+            ".long 0xe85250d6", // magic
+            ".long 0",          // architecture: i386 protected mode
+            ".long multiboot2_header_end - multiboot2_header",
+            ".long 0x100000000 - (0xe85250d6 + (multiboot2_header_end - multiboot2_header))",
+            // information request tag
+            ".short 1, 0",
+            ".long 20",
+            ".long 1, 6, 8",
+            ".align 8",
+            // end tag
+            ".short 0, 0",
+            ".long 8",
+            "multiboot2_header_end:",
+            ".text",
+        );
+    };
+}
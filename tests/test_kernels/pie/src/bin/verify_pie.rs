@@ -0,0 +1,40 @@
+#![no_std] // don't link the Rust standard library
+#![no_main] // disable all Rust-level entry points
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use test_kernel_pie::{exit_qemu, QemuExitCode};
+
+entry_point!(kernel_main);
+
+static VALUE: u64 = 42;
+/// Requires a `R_X86_64_RELATIVE` relocation since the address of `VALUE` is not known at
+/// link time.
+static VALUE_REF: &u64 = &VALUE;
+
+fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
+    // verify that the kernel was not loaded at its link address
+    let kernel_main_addr = kernel_main as *const () as u64;
+    assert!(kernel_main_addr >= 0x80_0000_0000);
+
+    // verify that the reported kernel base is correct
+    let kernel_base = boot_info.kernel_base;
+    assert_ne!(kernel_base, 0);
+    assert!(kernel_main_addr > kernel_base);
+    assert_eq!(kernel_base % 4096, 0);
+
+    // verify that relocations were applied
+    assert_eq!(VALUE_REF as *const u64, &VALUE as *const u64);
+    assert_eq!(*VALUE_REF, 42);
+
+    exit_qemu(QemuExitCode::Success);
+}
+
+/// This function is called on panic.
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    use core::fmt::Write;
+
+    let _ = writeln!(test_kernel_pie::serial(), "PANIC: {}", info);
+    exit_qemu(QemuExitCode::Failed);
+}
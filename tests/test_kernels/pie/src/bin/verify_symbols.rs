@@ -0,0 +1,31 @@
+#![no_std] // don't link the Rust standard library
+#![no_main] // disable all Rust-level entry points
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use test_kernel_pie::{exit_qemu, QemuExitCode};
+
+entry_point!(kernel_main);
+
+fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
+    let symbols = boot_info.kernel_symbols.into_option().unwrap();
+    assert!(!unsafe { symbols.symtab() }.is_empty());
+    assert!(!unsafe { symbols.strtab() }.is_empty());
+
+    // the symbol table contains link-time addresses
+    let kernel_main_addr = kernel_main as *const () as u64 - boot_info.kernel_base;
+    let (name, offset) = unsafe { symbols.lookup(kernel_main_addr + 1) }.unwrap();
+    assert!(name.contains("kernel_main"), "unexpected symbol `{}`", name);
+    assert_eq!(offset, 1);
+
+    exit_qemu(QemuExitCode::Success);
+}
+
+/// This function is called on panic.
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    use core::fmt::Write;
+
+    let _ = writeln!(test_kernel_pie::serial(), "PANIC: {}", info);
+    exit_qemu(QemuExitCode::Failed);
+}